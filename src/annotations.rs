@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Annotation {
+	pub name: Option<String>,
+	pub comment: Option<String>,
+}
+
+/// A bare string is a name, `{"name": .., "comment": ..}` allows both
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+	Name(String),
+	Full(Annotation),
+}
+
+/// User names and comments for addresses, loaded from a json sidecar file like
+/// `{"6027": "teleporter_check", "6030": {"comment": "r0 = r1 + 1"}}`
+#[derive(Debug, Clone, Default)]
+pub struct Annotations {
+	by_addr: BTreeMap<usize, Annotation>,
+}
+
+impl Annotations {
+	pub fn from_json(json: &str) -> anyhow::Result<Self> {
+		let raw: BTreeMap<String, Entry> = serde_json::from_str(json)?;
+		let mut by_addr = BTreeMap::new();
+		for (k, v) in raw {
+			let addr: usize = k.trim().parse().with_context(|| format!("Invalid address: {}", k))?;
+			if addr > 32767 {
+				bail!("Address out of range: {}", addr);
+			}
+			let ann = match v {
+				Entry::Name(name) => Annotation { name: Some(name), comment: None },
+				Entry::Full(a) => a,
+			};
+			by_addr.insert(addr, ann);
+		}
+		Ok(Self { by_addr })
+	}
+
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let content = std::fs::read_to_string(path)
+			.with_context(|| format!("Reading annotations {:?}", path))?;
+		Self::from_json(&content)
+	}
+
+	pub fn name(&self, addr: usize) -> Option<&str> {
+		self.by_addr.get(&addr)?.name.as_deref()
+	}
+
+	pub fn comment(&self, addr: usize) -> Option<&str> {
+		self.by_addr.get(&addr)?.comment.as_deref()
+	}
+
+	/// Address named `name`
+	pub fn resolve(&self, name: &str) -> Option<usize> {
+		self.by_addr.iter()
+			.find(|(_, a)| a.name.as_deref() == Some(name))
+			.map(|(addr, _)| *addr)
+	}
+
+	/// Address from a number or a name, e.g. a debugger argument
+	pub fn parse_addr(&self, s: &str) -> anyhow::Result<usize> {
+		let s = s.trim();
+		let addr = match s.parse() {
+			Ok(x) => x,
+			Err(_) => self.resolve(s).with_context(|| format!("Unknown address or label: {}", s))?,
+		};
+		if addr > 32767 {
+			bail!("Address out of range: {}", addr);
+		}
+		Ok(addr)
+	}
+
+	/// Name of `addr` if there is one, otherwise the number
+	pub fn symbol(&self, addr: usize) -> String {
+		match self.name(addr) {
+			Some(n) => n.to_string(),
+			None => addr.to_string(),
+		}
+	}
+}
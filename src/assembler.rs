@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use crate::op_parser::*;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Word(String),
+	Str(String),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+	Word(u16),
+	Label(String),
+}
+
+struct Item {
+	line: usize,
+	/// Address of the first word
+	addr: usize,
+	/// Whether the words form an instruction (validated with `Op::parse`)
+	is_op: bool,
+	operands: Vec<Operand>,
+}
+
+fn unescape(c: char) -> anyhow::Result<char> {
+	Ok(match c {
+		'n' => '\n',
+		't' => '\t',
+		'0' => '\0',
+		'\\' | '\'' | '"' => c,
+		_ => bail!("Unknown escape: \\{}", c),
+	})
+}
+
+/// Split a line into words and quoted strings, dropping `;` comments.
+/// Char literals (`'a'`) are returned as words including the quotes.
+fn tokenize(line: &str) -> anyhow::Result<Vec<Token>> {
+	let mut rv = vec![];
+	let mut chars = line.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			';' => break,
+			c if c.is_whitespace() || c == ',' => continue,
+			'"' | '\'' => {
+				let mut s = String::new();
+				loop {
+					match chars.next() {
+						None => bail!("Unterminated literal"),
+						Some(x) if x == c => break,
+						Some('\\') => s.push(unescape(chars.next().context("Unterminated literal")?)?),
+						Some(x) => s.push(x),
+					}
+				}
+				match c {
+					'"' => rv.push(Token::Str(s)),
+					_ => rv.push(Token::Word(format!("'{}'", s))),
+				}
+			},
+			_ => {
+				let mut s = c.to_string();
+				while let Some(x) = chars.peek() {
+					if x.is_whitespace() || *x == ',' || *x == ';' {
+						break;
+					}
+					s.push(*x);
+					chars.next();
+				}
+				rv.push(Token::Word(s));
+			}
+		}
+	}
+	Ok(rv)
+}
+
+fn parse_operand(s: &str) -> anyhow::Result<Operand> {
+	if let Some(r) = s.strip_prefix('r') {
+		if let Ok(r) = r.parse::<u16>() {
+			if r >= 8 {
+				bail!("Unknown register: {}", s);
+			}
+			return Ok(Operand::Word(32768 + r));
+		}
+	}
+	let num = if let Some(c) = s.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+		let mut chars = c.chars();
+		match (chars.next(), chars.next()) {
+			(Some(c), None) if c.is_ascii() => c as u16,
+			_ => bail!("Invalid char literal: {}", s),
+		}
+	} else if let Some(hex) = s.strip_prefix("0x") {
+		u16::from_str_radix(hex, 16).with_context(|| format!("Invalid number: {}", s))?
+	} else if s.starts_with(|c: char| c.is_ascii_digit()) {
+		s.parse().with_context(|| format!("Invalid number: {}", s))?
+	} else {
+		return Ok(Operand::Label(s.to_string()));
+	};
+	if num > 32767 {
+		bail!("Literal out of range: {}", s);
+	}
+	Ok(Operand::Word(num))
+}
+
+fn parse_line(tokens: &[Token], addr: usize, line: usize) -> anyhow::Result<Option<Item>> {
+	let (head, rest) = match tokens.split_first() {
+		None => return Ok(None),
+		Some(x) => x,
+	};
+	let head = match head {
+		Token::Word(w) => w.as_str(),
+		Token::Str(_) => bail!("Expected mnemonic or directive"),
+	};
+	let mut operands = vec![];
+	let is_op = match head {
+		".word" => {
+			for t in rest {
+				match t {
+					Token::Word(w) => operands.push(parse_operand(w)?),
+					Token::Str(_) => bail!(".word takes numbers, labels or chars"),
+				}
+			}
+			false
+		},
+		".ascii" => {
+			for t in rest {
+				match t {
+					Token::Str(s) => operands.extend(s.chars().map(|c| Operand::Word(c as u16))),
+					Token::Word(_) => bail!(".ascii takes quoted strings"),
+				}
+			}
+			false
+		},
+		_ => {
+			let opcode = OPCODES.iter()
+				.position(|(name, _)| *name == head)
+				.with_context(|| format!("Unknown mnemonic: {}", head))?;
+			let arity = OPCODES[opcode].1;
+			if rest.len() != arity {
+				bail!("{} takes {} operands, got {}", head, arity, rest.len());
+			}
+			operands.push(Operand::Word(opcode as u16));
+			for t in rest {
+				match t {
+					Token::Word(w) => operands.push(parse_operand(w)?),
+					Token::Str(_) => bail!("Strings are only allowed in .ascii"),
+				}
+			}
+			true
+		}
+	};
+	Ok(Some(Item { line, addr, is_op, operands }))
+}
+
+/// Assemble source text into memory words.
+///
+/// Lines hold an optional `label:`, then a mnemonic with its operands or a `.word`/`.ascii`
+/// directive. Operands are `r0..r7`, decimal or `0x` numbers, `'c'` chars or labels.
+/// Everything after `;` is a comment.
+pub fn assemble(source: &str) -> anyhow::Result<Vec<u16>> {
+	let mut labels: HashMap<String, usize> = HashMap::new();
+	let mut items = vec![];
+	let mut addr = 0;
+	for (i, line) in source.lines().enumerate() {
+		let line_no = i + 1;
+		let res: anyhow::Result<()> = (|| {
+			let mut tokens = tokenize(line)?;
+			while let Some(Token::Word(w)) = tokens.first() {
+				let label = match w.strip_suffix(':') {
+					Some(l) => l.to_string(),
+					None => break,
+				};
+				if labels.insert(label.clone(), addr).is_some() {
+					bail!("Duplicate label: {}", label);
+				}
+				tokens.remove(0);
+			}
+			if let Some(item) = parse_line(&tokens, addr, line_no)? {
+				addr += item.operands.len();
+				items.push(item);
+			}
+			Ok(())
+		})();
+		res.with_context(|| format!("line {}", line_no))?;
+	}
+	if addr > 32768 {
+		bail!("Program too large: {} words", addr);
+	}
+
+	let mut rv = Vec::with_capacity(addr);
+	for item in items.iter() {
+		for op in item.operands.iter() {
+			rv.push(match op {
+				Operand::Word(w) => *w,
+				Operand::Label(l) => *labels.get(l)
+					.with_context(|| format!("line {}: Unknown label: {}", item.line, l))? as u16,
+			});
+		}
+		if item.is_op {
+			let mut window = [0u16; 4];
+			window[..item.operands.len()].copy_from_slice(&rv[item.addr..]);
+			Op::parse(&window).with_context(|| format!("line {}: Invalid operands", item.line))?;
+		}
+	}
+	Ok(rv)
+}
+
+/// Little-endian image as read by `ExecutionEnv::new`
+pub fn to_bytes(words: &[u16]) -> Vec<u8> {
+	words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vm::{ExecutionEnv, Screen};
+
+	#[test]
+	fn test_assemble() {
+		let source = "
+			start:  set r0, 'A'   ; comment
+			        call print
+			        halt
+			print:  out r0
+			        ret
+			data:   .word 1 0x10 start
+			        .ascii \"a\\n\"
+		";
+		let words = assemble(source).unwrap();
+		assert_eq!(words, vec![1, 32768, 65, 17, 6, 0, 19, 32768, 18, 1, 16, 0, 97, 10]);
+	}
+
+	#[test]
+	fn test_errors() {
+		assert!(assemble("set 1 2").is_err());
+		assert!(assemble("jmp nowhere").is_err());
+		assert!(assemble("out 32768").is_err());
+		assert!(assemble("add r0 r1").is_err());
+		let err = assemble("noop\nfoo r0").unwrap_err();
+		assert_eq!(err.to_string(), "line 2");
+	}
+
+	#[test]
+	fn test_runs_in_vm() {
+		let words = assemble("set r1 3\nloop: out 'x'\nadd r1 r1 32767\njt r1 loop\nout 10").unwrap();
+		let (screen, mut user) = Screen::create();
+		let mut env = ExecutionEnv::new(&to_bytes(&words), screen, None);
+		env.run().unwrap();
+		assert_eq!(user.get_all().unwrap(), "xxx\n");
+	}
+}
@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use serde::Serialize;
+use crate::annotations::Annotations;
+use crate::cfg::Cfg;
+use crate::op_parser::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CallTarget {
+	Direct(usize),
+	/// `call` through a register, the register number is kept
+	Indirect(usize),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallSite {
+	/// Address of the `call` instruction
+	pub addr: usize,
+	/// Entry of the function containing the call
+	pub caller: usize,
+	pub target: CallTarget,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Function {
+	pub entry: usize,
+	/// Lowest address and one past the last word of the function's instructions
+	pub extent: (usize, usize),
+	pub callees: BTreeSet<usize>,
+	pub callers: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CallGraph {
+	pub functions: BTreeMap<usize, Function>,
+	pub calls: Vec<CallSite>,
+}
+
+impl CallGraph {
+	/// Discover functions starting from `roots` (treated as function entries),
+	/// following every direct `call` target.
+	pub fn discover(memory: &[u16], roots: &[usize]) -> Self {
+		let mut rv = Self::default();
+		let mut pending: Vec<usize> = roots.to_vec();
+		while let Some(entry) = pending.pop() {
+			if rv.functions.contains_key(&entry) || entry >= memory.len() {
+				continue;
+			}
+			let cfg = Cfg::build(memory, entry);
+			let mut extent = (entry, entry);
+			let mut callees = BTreeSet::new();
+			for (addr, op) in cfg.blocks.values().flat_map(|b| b.ops.iter()) {
+				extent.0 = extent.0.min(*addr);
+				extent.1 = extent.1.max(addr + op.param_bytes() as usize);
+				let target = match op {
+					Op::Call(Val::Num(m)) => CallTarget::Direct(m.to_usize()),
+					Op::Call(Val::Reg(r)) => CallTarget::Indirect(r.to_usize()),
+					_ => continue,
+				};
+				if let CallTarget::Direct(t) = target {
+					callees.insert(t);
+					pending.push(t);
+				}
+				rv.calls.push(CallSite { addr: *addr, caller: entry, target });
+			}
+			rv.functions.insert(entry, Function { entry, extent, callees, callers: BTreeSet::new() });
+		}
+		for call in rv.calls.iter() {
+			if let CallTarget::Direct(t) = call.target {
+				if let Some(f) = rv.functions.get_mut(&t) {
+					f.callers.insert(call.caller);
+				}
+			}
+		}
+		rv.calls.sort_by_key(|c| c.addr);
+		rv
+	}
+
+	/// Calls through registers, which can't be resolved statically
+	pub fn unresolved(&self) -> impl Iterator<Item = &CallSite> {
+		self.calls.iter().filter(|c| matches!(c.target, CallTarget::Indirect(_)))
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string_pretty(self).unwrap()
+	}
+
+	/// Functions as nodes, direct calls as edges and register calls as dashed
+	/// edges into a single `indirect` node
+	pub fn to_dot(&self, ann: &Annotations) -> String {
+		let mut rv = String::new();
+		writeln!(rv, "digraph calls {{").unwrap();
+		writeln!(rv, "\tnode [shape=box fontname=\"monospace\"];").unwrap();
+		for f in self.functions.values() {
+			writeln!(rv, "\tf{} [label=\"{}\\n{}..{}\"];", f.entry, ann.symbol(f.entry), f.extent.0, f.extent.1).unwrap();
+		}
+		for f in self.functions.values() {
+			for callee in f.callees.iter() {
+				writeln!(rv, "\tf{} -> f{};", f.entry, callee).unwrap();
+			}
+		}
+		if self.unresolved().next().is_some() {
+			writeln!(rv, "\tindirect [label=\"?\" shape=ellipse];").unwrap();
+		}
+		for call in self.unresolved() {
+			if let CallTarget::Indirect(r) = call.target {
+				writeln!(rv, "\tf{} -> indirect [style=dashed label=\"r{} @{}\"];", call.caller, r, call.addr).unwrap();
+			}
+		}
+		writeln!(rv, "}}").unwrap();
+		rv
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_discover() {
+		// 0: call 5; 2: call r1; 4: halt; 5: call 8; 7: ret; 8: ret
+		let mem = [17, 5, 17, 32769, 0, 17, 8, 18, 18];
+		let graph = CallGraph::discover(&mem, &[0]);
+		let entries: Vec<usize> = graph.functions.keys().cloned().collect();
+		assert_eq!(entries, vec![0, 5, 8]);
+		assert_eq!(graph.functions[&0].extent, (0, 5));
+		assert_eq!(graph.functions[&8].callers, BTreeSet::from([5]));
+		let unresolved: Vec<usize> = graph.unresolved().map(|c| c.addr).collect();
+		assert_eq!(unresolved, vec![2]);
+	}
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::annotations::Annotations;
+use crate::op_parser::*;
+use crate::reverse_engineer::{decode_at, fmt_op, trace_code};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+	/// Unconditional `jmp`
+	Jump,
+	/// Condition of `jt`/`jf` held
+	Taken,
+	/// Next instruction in memory
+	Fallthrough,
+}
+
+impl EdgeKind {
+	fn label(&self) -> &'static str {
+		match self {
+			Self::Jump => "jmp",
+			Self::Taken => "taken",
+			Self::Fallthrough => "fall",
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+	pub start: usize,
+	pub ops: Vec<(usize, Op)>,
+	pub succs: Vec<(usize, EdgeKind)>,
+}
+
+/// Control-flow graph of a single function. Calls don't leave the function,
+/// they are kept inside blocks and execution continues after them.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+	pub entry: usize,
+	pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+fn is_block_end(op: &Op) -> bool {
+	matches!(op, Op::Jmp(_) | Op::Jt(_, _) | Op::Jf(_, _) | Op::Ret | Op::Halt)
+}
+
+/// Successors of `op` at `addr` inside its function
+fn successors(addr: usize, op: &Op) -> Vec<(usize, EdgeKind)> {
+	let next = addr + op.param_bytes() as usize;
+	match *op {
+		Op::Jmp(m) => vec![(m.to_usize(), EdgeKind::Jump)],
+		Op::Jt(_, m) | Op::Jf(_, m) => vec![(m.to_usize(), EdgeKind::Taken), (next, EdgeKind::Fallthrough)],
+		Op::Ret | Op::Halt => vec![],
+		_ => vec![(next, EdgeKind::Fallthrough)],
+	}
+}
+
+impl Cfg {
+	pub fn build(memory: &[u16], entry: usize) -> Self {
+		let mut instrs = BTreeMap::new();
+		let mut pending = vec![entry];
+		while let Some(addr) = pending.pop() {
+			if addr >= memory.len() || instrs.contains_key(&addr) {
+				continue;
+			}
+			let op = match decode_at(memory, addr) {
+				Ok(op) => op,
+				Err(_) => continue,
+			};
+			instrs.insert(addr, op);
+			pending.extend(successors(addr, &op).into_iter().map(|(a, _)| a));
+		}
+
+		let mut leaders = BTreeSet::from([entry]);
+		for (addr, op) in instrs.iter() {
+			if matches!(op, Op::Jmp(_) | Op::Jt(_, _) | Op::Jf(_, _)) {
+				leaders.extend(successors(*addr, op).into_iter().map(|(a, _)| a));
+			}
+		}
+
+		let mut blocks = BTreeMap::new();
+		for leader in leaders.iter().filter(|l| instrs.contains_key(l)) {
+			let mut ops = vec![];
+			let mut addr = *leader;
+			let mut succs = vec![];
+			while let Some(op) = instrs.get(&addr) {
+				ops.push((addr, *op));
+				let next = addr + op.param_bytes() as usize;
+				if is_block_end(op) || leaders.contains(&next) || !instrs.contains_key(&next) {
+					succs = successors(addr, op).into_iter()
+						.filter(|(a, _)| instrs.contains_key(a))
+						.collect();
+					break;
+				}
+				addr = next;
+			}
+			blocks.insert(*leader, BasicBlock { start: *leader, ops, succs });
+		}
+		Self { entry, blocks }
+	}
+
+	/// Write the graph as a DOT cluster, to be embedded in a digraph
+	pub fn write_dot(&self, rv: &mut String, ann: &Annotations) {
+		writeln!(rv, "\tsubgraph cluster_{} {{", self.entry).unwrap();
+		writeln!(rv, "\t\tlabel=\"fn {}\";", ann.symbol(self.entry)).unwrap();
+		for block in self.blocks.values() {
+			let mut label = String::new();
+			for (addr, op) in block.ops.iter() {
+				let _ = write!(label, "{:>5}: {}\\l", addr, fmt_op(op, ann));
+			}
+			writeln!(rv, "\t\tb{}_{} [label=\"{}\"];", self.entry, block.start, label).unwrap();
+			for (succ, kind) in block.succs.iter() {
+				writeln!(rv, "\t\tb{}_{} -> b{}_{} [label=\"{}\"];", self.entry, block.start, self.entry, succ, kind.label()).unwrap();
+			}
+		}
+		writeln!(rv, "\t}}").unwrap();
+	}
+}
+
+/// Static call targets reachable from `roots`, plus the roots themselves
+pub fn function_entries(memory: &[u16], roots: &[usize]) -> BTreeSet<usize> {
+	let mut rv: BTreeSet<usize> = roots.iter().cloned().collect();
+	for op in trace_code(memory, roots).values() {
+		if let Op::Call(Val::Num(m)) = op {
+			rv.insert(m.to_usize());
+		}
+	}
+	rv
+}
+
+pub fn to_dot(cfgs: &[Cfg], ann: &Annotations) -> String {
+	let mut rv = String::new();
+	writeln!(rv, "digraph cfg {{").unwrap();
+	writeln!(rv, "\tnode [shape=box fontname=\"monospace\"];").unwrap();
+	for cfg in cfgs {
+		cfg.write_dot(&mut rv, ann);
+	}
+	writeln!(rv, "}}").unwrap();
+	rv
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_blocks() {
+		// 0: jt r0 5; 3: call 9; 5: out r1; 7: ret;
+		let mem = [7, 32768, 5, 17, 9, 19, 32769, 18];
+		let cfg = Cfg::build(&mem, 0);
+		let starts: Vec<usize> = cfg.blocks.keys().cloned().collect();
+		assert_eq!(starts, vec![0, 3, 5]);
+		assert_eq!(cfg.blocks[&0].succs, vec![(5, EdgeKind::Taken), (3, EdgeKind::Fallthrough)]);
+		assert_eq!(cfg.blocks[&3].ops.len(), 1);
+		assert_eq!(cfg.blocks[&3].succs, vec![(5, EdgeKind::Fallthrough)]);
+		assert_eq!(cfg.blocks[&5].ops.len(), 2);
+		assert!(cfg.blocks[&5].succs.is_empty());
+	}
+}
@@ -0,0 +1,176 @@
+//! Checkpoints are the json list of lines a game session saves: game commands, and the
+//! `!reg`/`!poke` writes made from the prompt between them.
+//!
+//! Every front end replays them with [`replay`], so a write lands at the same point of the
+//! game as when it was made rather than being typed into the game as input.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::op_parser::*;
+use crate::vm::ExecutionEnv;
+use crate::vm_io::Io;
+
+/// A register or memory write made at the prompt, `!reg r7 25734` or `!poke 2732 0`.
+/// Registers take 15-bit values like the debugger's `set`, memory words can also
+/// hold register references (32768..=32775) like an instruction operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeWrite {
+    Reg(Reg, MemBlock),
+    Poke(usize, MemBlock),
+}
+
+impl RuntimeWrite {
+    pub fn apply<S: Io>(&self, env: &mut ExecutionEnv<S>) -> anyhow::Result<()> {
+        match *self {
+            Self::Reg(_, val) if val > 32767 => bail!("Value out of range: {}", val),
+            Self::Poke(_, val) if val > 32775 => bail!("Value out of range: {}", val),
+            Self::Reg(reg, val) => env.set_register(reg.to_usize(), val),
+            Self::Poke(addr, val) => {
+                let mem: Mem = u16::try_from(addr).ok().and_then(|a| a.try_into().ok())
+                    .with_context(|| format!("Address out of range: {}", addr))?;
+                env.poke(mem, val);
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RuntimeWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reg(reg, val) => write!(f, "!reg {} {}", reg, val),
+            Self::Poke(addr, val) => write!(f, "!poke {} {}", addr, val),
+        }
+    }
+}
+
+impl FromStr for RuntimeWrite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let args: Vec<&str> = s.trim().strip_prefix('!').unwrap_or("").split_whitespace().collect();
+        Ok(match args[..] {
+            ["reg", reg, val] => match (reg.parse(), val.parse()) {
+                (Ok(reg), Ok(val)) => Self::Reg(reg, val),
+                _ => bail!(">> Usage: !reg <r0-r7> <value>"),
+            },
+            ["poke", addr, val] => match (addr.parse(), val.parse()) {
+                (Ok(addr), Ok(val)) => Self::Poke(addr, val),
+                _ => bail!(">> Usage: !poke <address> <value>"),
+            },
+            _ => bail!(">> Unknown command, try !reg, !peek or !poke"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    /// Game input, with its newline
+    Input(String),
+    Write(RuntimeWrite),
+}
+
+impl FromStr for Line {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.trim_start().starts_with('!') {
+            true => Self::Write(s.parse()?),
+            false => Self::Input(s.to_string()),
+        })
+    }
+}
+
+pub fn parse(lines: &[String]) -> anyhow::Result<Vec<Line>> {
+    lines.iter().enumerate()
+        .map(|(i, l)| l.parse().with_context(|| format!("Checkpoint line {}: {:?}", i + 1, l)))
+        .collect()
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Vec<Line>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Reading checkpoint {:?}", path))?;
+    let lines: Vec<String> = serde_json::from_str(&content)?;
+    parse(&lines)
+}
+
+/// Replay `lines` on `env`, with `send` queuing game input for it. Before each write
+/// the game runs until it read everything sent so far and waits for more, which is
+/// where the write was made. Input after the last write is only queued.
+pub fn replay<S: Io>(
+    env: &mut ExecutionEnv<S>,
+    lines: &[Line],
+    mut send: impl FnMut(&mut ExecutionEnv<S>, &str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Input(text) => send(env, text)?,
+            Line::Write(w) => {
+                if env.run_until_empty()? {
+                    bail!("The game ended before checkpoint line {}: {}", i + 1, w);
+                }
+                w.apply(env)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::vm_io::BufferIo;
+
+    #[test]
+    fn test_parse() {
+        let lines = ["go north\n", "!reg r7 25734", " !poke 2732 0\n"].map(String::from);
+        assert_eq!(parse(&lines).unwrap(), vec![
+            Line::Input("go north\n".into()),
+            Line::Write(RuntimeWrite::Reg("r7".parse().unwrap(), 25734)),
+            Line::Write(RuntimeWrite::Poke(2732, 0)),
+        ]);
+        assert_eq!(RuntimeWrite::Poke(2732, 0).to_string(), "!poke 2732 0");
+        for bad in ["!reg r8 1", "!poke 1", "!peek 1 2", "!reg r0 x"] {
+            assert!(parse(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        // Echoes each line, with r7 as the first char
+        let words = assemble("
+            loop: in r0
+            eq r1 r0 10
+            jt r1 line
+            out r0
+            jmp loop
+            line: out r7
+            out 10
+            jmp loop
+        ").unwrap();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), BufferIo::new(""), None);
+        let lines = parse(&["a\n", "!reg r7 66", "c\n", "!poke 100 7", "d\n"].map(String::from)).unwrap();
+        replay(&mut env, &lines, |env, s| {
+            env.screen.input.extend(s.chars());
+            Ok(())
+        }).unwrap();
+        // The last line is queued, not run
+        assert_eq!(env.screen.output, "a\0\ncB\n");
+        assert_eq!(env.screen.input.iter().collect::<String>(), "d\n");
+        env.run_until_empty().unwrap();
+        assert_eq!(env.screen.output, "a\0\ncB\ndB\n");
+        assert_eq!(env.memory[100], 7);
+
+        let mut env = ExecutionEnv::new(&to_bytes(&[0]), BufferIo::new(""), None);
+        assert!(replay(&mut env, &lines[..2], |_, _| Ok(())).is_err());
+        assert!(RuntimeWrite::Reg("r0".parse().unwrap(), 32768).apply(&mut env).is_err());
+        assert!(RuntimeWrite::Poke(32768, 0).apply(&mut env).is_err());
+        assert!(RuntimeWrite::Poke(0, 32776).apply(&mut env).is_err());
+        RuntimeWrite::Poke(0, 32775).apply(&mut env).unwrap();
+        assert_eq!(env.memory[0], 32775);
+    }
+}
@@ -0,0 +1,352 @@
+//! Breakpoint conditions like `pc == 6027 && r7 != 0`, `mem[3952] > 5`, `ops > 701000`
+//! or `stack.len() > 100`, parsed at runtime and evaluated against an `ExecutionEnv`.
+//!
+//! Operands are numbers, `pc`, `ops` (operation count), `r0`..`r7`, `mem[expr]`,
+//! `stack[expr]` (0 is the bottom) and `stack.len()`. Operators by increasing precedence:
+//! `||`, `&&`, comparisons, `+ -`, `*`, then the unary `!` and `-`. Comparisons give 1 or 0
+//! and anything non-zero is true. Reads past the end of memory or the stack give 0.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::error::VmError;
+use crate::op_parser::IntoUsize;
+use crate::vm::{ExecutionEnv, StepOutcome};
+use crate::vm_io::Io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    Pc,
+    Ops,
+    Reg(usize),
+    Mem(Box<Expr>),
+    Stack(Box<Expr>),
+    StackLen,
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    /// Operators and punctuation
+    Sym(&'static str),
+}
+
+/// Longest first so `<=` isn't read as `<`
+const SYMBOLS: [&str; 17] = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "!", "(", ")", "[", "]", "."];
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    let mut rv = vec![];
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let word = &rest[..len];
+            rv.push(match c.is_ascii_digit() {
+                true => {
+                    let n = match word.strip_prefix("0x") {
+                        Some(hex) => i64::from_str_radix(hex, 16),
+                        None => word.parse(),
+                    };
+                    Token::Num(n.with_context(|| format!("Invalid number: {}", word))?)
+                },
+                false => Token::Ident(word.to_string()),
+            });
+            rest = &rest[len..];
+        } else {
+            let sym = match SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+                Some(s) => s,
+                None => bail!("Unexpected {:?} at {:?}", c, rest),
+            };
+            rv.push(Token::Sym(sym));
+            rest = &rest[sym.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(rv)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, sym: &str) -> bool {
+        match self.peek() {
+            Some(Token::Sym(s)) if *s == sym => {
+                self.pos += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, sym: &str) -> anyhow::Result<()> {
+        if !self.eat(sym) {
+            bail!("Expected `{}`, found {}", sym, self.found());
+        }
+        Ok(())
+    }
+
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(Token::Num(n)) => format!("`{}`", n),
+            Some(Token::Ident(s)) => format!("`{}`", s),
+            Some(Token::Sym(s)) => format!("`{}`", s),
+            None => "the end".to_string(),
+        }
+    }
+
+    /// Left associative binary operators of one precedence level
+    fn binary(&mut self, ops: &[(&str, BinOp)], next: fn(&mut Self) -> anyhow::Result<Expr>) -> anyhow::Result<Expr> {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for (sym, op) in ops {
+                if self.eat(sym) {
+                    lhs = Expr::Bin(*op, Box::new(lhs), Box::new(next(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn or(&mut self) -> anyhow::Result<Expr> {
+        self.binary(&[("||", BinOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expr> {
+        self.binary(&[("&&", BinOp::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> anyhow::Result<Expr> {
+        use BinOp::*;
+        self.binary(&[("==", Eq), ("!=", Ne), ("<=", Le), (">=", Ge), ("<", Lt), (">", Gt)], Self::sum)
+    }
+
+    fn sum(&mut self) -> anyhow::Result<Expr> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> anyhow::Result<Expr> {
+        self.binary(&[("*", BinOp::Mul)], Self::unary)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn index(&mut self) -> anyhow::Result<Box<Expr>> {
+        self.expect("[")?;
+        let rv = self.or()?;
+        self.expect("]")?;
+        Ok(Box::new(rv))
+    }
+
+    fn atom(&mut self) -> anyhow::Result<Expr> {
+        let token = match self.peek() {
+            Some(t) => t.clone(),
+            None => bail!("Expected a value, found the end"),
+        };
+        self.pos += 1;
+        Ok(match token {
+            Token::Num(n) => Expr::Num(n),
+            Token::Sym("(") => {
+                let rv = self.or()?;
+                self.expect(")")?;
+                rv
+            },
+            Token::Ident(name) => match name.as_str() {
+                "pc" => Expr::Pc,
+                "ops" => Expr::Ops,
+                "mem" => Expr::Mem(self.index()?),
+                "stack" if self.eat(".") => {
+                    match self.peek() {
+                        Some(Token::Ident(s)) if s == "len" => self.pos += 1,
+                        _ => bail!("Expected `len`, found {}", self.found()),
+                    }
+                    self.expect("(")?;
+                    self.expect(")")?;
+                    Expr::StackLen
+                },
+                "stack" => Expr::Stack(self.index()?),
+                _ => match name.strip_prefix('r').and_then(|r| r.parse().ok()) {
+                    Some(r) if r < 8 => Expr::Reg(r),
+                    _ => bail!("Unknown name `{}`", name),
+                },
+            },
+            Token::Sym(s) => bail!("Expected a value, found `{}`", s),
+        })
+    }
+}
+
+impl Expr {
+    pub fn eval<S: Io>(&self, env: &ExecutionEnv<S>) -> i64 {
+        use BinOp::*;
+        match self {
+            Self::Num(n) => *n,
+            Self::Pc => env.curr_point.to_usize() as i64,
+            Self::Ops => env.operation_count as i64,
+            Self::Reg(r) => env.registers[*r] as i64,
+            Self::Mem(e) => index(&env.memory, e.eval(env)),
+            Self::Stack(e) => index(&env.stack, e.eval(env)),
+            Self::StackLen => env.stack.len() as i64,
+            Self::Not(e) => (e.eval(env) == 0) as i64,
+            Self::Neg(e) => e.eval(env).wrapping_neg(),
+            Self::Bin(op, a, b) => {
+                let a = a.eval(env);
+                // Short-circuit, `b` may be expensive or only meaningful when `a` holds
+                match op {
+                    Or if a != 0 => return 1,
+                    And if a == 0 => return 0,
+                    _ => {},
+                }
+                let b = b.eval(env);
+                match op {
+                    Or | And => (b != 0) as i64,
+                    Eq => (a == b) as i64,
+                    Ne => (a != b) as i64,
+                    Lt => (a < b) as i64,
+                    Le => (a <= b) as i64,
+                    Gt => (a > b) as i64,
+                    Ge => (a >= b) as i64,
+                    Add => a.wrapping_add(b),
+                    Sub => a.wrapping_sub(b),
+                    Mul => a.wrapping_mul(b),
+                }
+            },
+        }
+    }
+}
+
+fn index(words: &[u16], i: i64) -> i64 {
+    usize::try_from(i).ok().and_then(|i| words.get(i)).map(|w| *w as i64).unwrap_or(0)
+}
+
+/// A parsed condition, displayed as it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    pub expr: Expr,
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0 };
+        let expr = parser.or().with_context(|| format!("In condition `{}`", s.trim()))?;
+        if parser.peek().is_some() {
+            bail!("Unexpected {} in condition `{}`", parser.found(), s.trim());
+        }
+        Ok(Self { source: s.trim().to_string(), expr })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Condition {
+    pub fn eval<S: Io>(&self, env: &ExecutionEnv<S>) -> bool {
+        self.expr.eval(env) != 0
+    }
+}
+
+/// Whether a breakpoint in `breakpoints` stops `env` at its current pc,
+/// unconditional ones always do and conditional ones when their condition holds
+pub fn breaks_at<S: Io>(breakpoints: &BTreeMap<usize, Option<Condition>>, env: &ExecutionEnv<S>) -> bool {
+    match breakpoints.get(&env.curr_point.to_usize()) {
+        Some(Some(c)) => c.eval(env),
+        Some(None) => true,
+        None => false,
+    }
+}
+
+impl<S: Io> ExecutionEnv<S> {
+    /// Step until `condition` holds after a step or the outcome is final
+    pub fn run_until_expr(&mut self, condition: &Condition) -> Result<StepOutcome, VmError> {
+        self.run_until(|env, _| condition.eval(env))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::vm_io::BufferIo;
+
+    #[test]
+    fn test_parse() {
+        let c: Condition = "pc == 6027 && r7 != 0".parse().unwrap();
+        assert_eq!(c.expr, Expr::Bin(BinOp::And,
+            Box::new(Expr::Bin(BinOp::Eq, Box::new(Expr::Pc), Box::new(Expr::Num(6027)))),
+            Box::new(Expr::Bin(BinOp::Ne, Box::new(Expr::Reg(7)), Box::new(Expr::Num(0))))));
+        assert_eq!(c.to_string(), "pc == 6027 && r7 != 0");
+        let c: Condition = "1 + 2 * 3 == 7 || !stack.len()".parse().unwrap();
+        assert!(matches!(c.expr, Expr::Bin(BinOp::Or, _, _)));
+        for bad in ["r8 > 1", "mem[3", "pc ==", "stack.size()", "1 2", "pc = 1", ""] {
+            assert!(bad.parse::<Condition>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let words = assemble("
+            set r0 3
+            loop: push r0
+            wmem 100 r0
+            add r0 r0 32767
+            jt r0 loop
+            halt
+        ").unwrap();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), BufferIo::new(""), None);
+        let eval = |env: &ExecutionEnv<BufferIo>, s: &str| s.parse::<Condition>().unwrap().eval(env);
+
+        let step = env.run_until_expr(&"mem[100] == 2 && stack.len() > 1".parse().unwrap()).unwrap();
+        assert_eq!(step.pc_after, 8);
+        assert!(eval(&env, "stack[0] == 3 && stack[1] == 2 && stack[5] == 0"));
+        assert!(eval(&env, "ops == 7 && pc == 8 && r0 - 1 == 1"));
+        assert!(!eval(&env, "mem[-1] || mem[40000] || -r0 > 0"));
+
+        let mut breakpoints = BTreeMap::new();
+        breakpoints.insert(3, Some("r0 == 1".parse().unwrap()));
+        let step = env.run_until(|env, _| breaks_at(&breakpoints, env)).unwrap();
+        assert_eq!((step.pc_after, env.registers[0]), (3, 1));
+    }
+}
@@ -0,0 +1,591 @@
+//! Debug Adapter Protocol server over stdio, for debugging guest code from an editor.
+//!
+//! Breakpoints are set as function breakpoints (an annotation label or an address) or
+//! instruction breakpoints, source breakpoints aren't supported as there is no source.
+//! Both take an optional `condition` in the expression language of [`crate::condition`].
+//! Instruction and memory references are word addresses in decimal, `readMemory`
+//! offsets are in bytes with each word little-endian. Game output is sent as `output`
+//! events and expressions evaluated in the debug console are sent to the game as input.
+//!
+//! Editors start it as the `dap` subcommand of the main binary. `launch` takes `program`
+//! (`challenge.bin`), `register8` (25734, up to 32767), `checkpoint` and `stopOnEntry`.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+
+use crate::annotations::Annotations;
+use crate::checkpoint;
+use crate::condition::{breaks_at, Condition};
+use crate::op_parser::*;
+use crate::patch::PatchSet;
+use crate::reverse_engineer::{decode_at, fmt_op, return_addresses};
+use crate::undo::UndoConfig;
+use crate::vm::{ExecutionEnv, Screen, StepOutcome};
+
+const THREAD_ID: u64 = 1;
+
+/// Instructions run between checks for requests (like `pause`) while running
+const POLL_INTERVAL: u64 = 100_000;
+
+/// Variable references of the scopes
+const REGISTERS_REF: u64 = 1;
+const STACK_REF: u64 = 2;
+const MEMORY_REF: u64 = 3;
+
+/// Words shown in the memory scope, starting at the pc
+const MEMORY_WORDS: usize = 16;
+
+/// Next message from `input`, `None` at the end of the stream
+pub fn read_message(input: &mut impl BufRead) -> anyhow::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(v) = line.strip_prefix("Content-Length:") {
+            length = Some(v.trim().parse::<usize>().context("Invalid Content-Length")?);
+        }
+    }
+    let mut body = vec![0u8; length.context("Missing Content-Length")?];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(out: &mut impl Write, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rv = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => rv.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => rv.push('='),
+            }
+        }
+    }
+    rv
+}
+
+/// What the program is doing between requests
+#[derive(Debug, Clone, Copy)]
+enum Run {
+    Continue,
+    /// Stepping over the call at the pc, until it returns to this address with this stack depth
+    Next(usize, usize),
+    /// Until a `ret` leaves the stack below this depth
+    StepOut(usize),
+}
+
+pub struct DapServer<'a, W: Write> {
+    out: W,
+    seq: u64,
+    ann: &'a Annotations,
+    /// Applied to every launched image
+    patches: &'a PatchSet,
+    env: Option<ExecutionEnv>,
+    user: Option<Screen>,
+    function_breakpoints: BTreeMap<usize, Option<Condition>>,
+    instruction_breakpoints: BTreeMap<usize, Option<Condition>>,
+    stop_on_entry: bool,
+    running: Option<Run>,
+}
+
+impl<'a, W: Write> DapServer<'a, W> {
+    pub fn new(out: W, ann: &'a Annotations, patches: &'a PatchSet) -> Self {
+        Self {
+            out,
+            seq: 0,
+            ann,
+            patches,
+            env: None,
+            user: None,
+            function_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: BTreeMap::new(),
+            stop_on_entry: false,
+            running: None,
+        }
+    }
+
+    /// Serve requests read from `input` until the client disconnects
+    pub fn serve(&mut self, mut input: impl BufRead + Send + 'static) -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Ok(Some(msg)) = read_message(&mut input) {
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let msg = match self.running {
+                Some(_) => match rx.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                },
+                None => match rx.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break,
+                },
+            };
+            if let Some(msg) = msg {
+                if !self.handle(&msg)? {
+                    break;
+                }
+            }
+            if let Some(run) = self.running {
+                self.run_batch(run)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut msg: Value) -> anyhow::Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        write_message(&mut self.out, &msg)?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> anyhow::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+
+    fn stopped(&mut self, reason: &str) -> anyhow::Result<()> {
+        self.running = None;
+        self.flush_output()?;
+        self.event("stopped", json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}))
+    }
+
+    fn terminated(&mut self) -> anyhow::Result<()> {
+        self.running = None;
+        self.flush_output()?;
+        self.event("exited", json!({"exitCode": 0}))?;
+        self.event("terminated", json!({}))
+    }
+
+    fn flush_output(&mut self) -> anyhow::Result<()> {
+        let output = match &mut self.user {
+            Some(user) => user.get_all()?,
+            None => return Ok(()),
+        };
+        if !output.is_empty() {
+            self.event("output", json!({"category": "stdout", "output": output}))?;
+        }
+        Ok(())
+    }
+
+    fn env(&mut self) -> anyhow::Result<&mut ExecutionEnv> {
+        self.env.as_mut().context("No program launched")
+    }
+
+    /// The kind of breakpoint hit at the current pc, if any
+    fn breakpoint_hit(&self) -> Option<&'static str> {
+        let env = self.env.as_ref()?;
+        if breaks_at(&self.function_breakpoints, env) {
+            Some("function breakpoint")
+        } else if breaks_at(&self.instruction_breakpoints, env) {
+            Some("instruction breakpoint")
+        } else {
+            None
+        }
+    }
+
+    /// Run up to `POLL_INTERVAL` instructions of `run`
+    fn run_batch(&mut self, run: Run) -> anyhow::Result<()> {
+        let mut env = self.env.take().context("No program launched")?;
+        let mut count = 0;
+        let mut done = false;
+        let rv = env.run_until(|env, step| {
+            count += 1;
+            done = match run {
+                Run::Continue => false,
+                Run::Next(ret, depth) => step.pc_after == ret && env.stack.len() == depth,
+                Run::StepOut(depth) => matches!(step.op, Op::Ret) && env.stack.len() < depth,
+            };
+            done || count >= POLL_INTERVAL
+                || breaks_at(&self.function_breakpoints, env)
+                || breaks_at(&self.instruction_breakpoints, env)
+        });
+        self.env = Some(env);
+        let step = match rv {
+            Ok(step) => step,
+            Err(e) => {
+                self.event("output", json!({"category": "stderr", "output": format!("{}\n", e)}))?;
+                return self.terminated();
+            }
+        };
+        self.after_step(&step, done, "step")
+    }
+
+    /// Report where `step` left the program, `finished` if the step request is done
+    fn after_step(&mut self, step: &StepOutcome, finished: bool, reason: &str) -> anyhow::Result<()> {
+        if step.halted {
+            return self.terminated();
+        }
+        if finished {
+            return self.stopped(reason);
+        }
+        if step.stopped {
+            return self.stopped("pause");
+        }
+        if step.waiting_for_input {
+            self.flush_output()?;
+            std::thread::sleep(Duration::from_millis(20));
+            return Ok(());
+        }
+        if let Some(kind) = self.breakpoint_hit() {
+            return self.stopped(kind);
+        }
+        self.flush_output()
+    }
+
+    /// Go back one instruction, or until a breakpoint if `until_breakpoint`.
+    /// Running out of history stops at its start
+    fn step_back(&mut self, until_breakpoint: bool) -> anyhow::Result<()> {
+        let mut env = self.env.take().context("No program launched")?;
+        let found = match until_breakpoint {
+            true => env.run_back_until(|env| breaks_at(&self.function_breakpoints, env) || breaks_at(&self.instruction_breakpoints, env)),
+            false => env.step_back(1) == 1,
+        };
+        self.env = Some(env);
+        let reason = match found && until_breakpoint {
+            true => self.breakpoint_hit().unwrap_or("breakpoint"),
+            false => "step",
+        };
+        self.stopped(reason)
+    }
+
+    /// Handle one request, returns false when the session is over
+    pub fn handle(&mut self, msg: &Value) -> anyhow::Result<bool> {
+        let command = msg["command"].as_str().unwrap_or("").to_string();
+        let args = &msg["arguments"];
+        let rv = self.request(&command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": msg["seq"],
+            "command": command,
+            "success": rv.is_ok(),
+        });
+        match &rv {
+            Ok(body) => response["body"] = body.clone(),
+            Err(e) => response["message"] = json!(e.to_string()),
+        }
+        self.send(response)?;
+
+        // Events that must follow the response
+        match (command.as_str(), rv.is_ok()) {
+            ("initialize", true) => self.event("initialized", json!({}))?,
+            ("configurationDone", true) => match self.stop_on_entry {
+                true => self.stopped("entry")?,
+                false => self.running = Some(Run::Continue),
+            },
+            ("stepIn", true) | ("next", true) if self.running.is_none() => {
+                let env = self.env()?;
+                let step = env.step()?;
+                self.after_step(&step, true, "step")?;
+            },
+            ("pause", true) => self.stopped("pause")?,
+            ("stepBack", true) | ("reverseContinue", true) if self.running.is_none() => {
+                self.step_back(command == "reverseContinue")?;
+            },
+            ("disconnect", _) | ("terminate", _) => return Ok(false),
+            _ => {},
+        }
+        Ok(true)
+    }
+
+    fn request(&mut self, command: &str, args: &Value) -> anyhow::Result<Value> {
+        Ok(match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSetVariable": true,
+                "supportsStepBack": true,
+                "supportsSteppingGranularity": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" => {
+                let program = args["program"].as_str().unwrap_or("challenge.bin");
+                let image = std::fs::read(program).with_context(|| format!("Reading {}", program))?;
+                let preset = match args["register8"].as_u64() {
+                    Some(v) if v > 32767 => bail!("register8 out of range: {}", v),
+                    Some(v) => v as u16,
+                    None => 25734,
+                };
+                let (screen, mut user) = Screen::create();
+                let mut env = ExecutionEnv::with_patches(&image, screen, Some(preset), self.patches)?;
+                if let Some(cp) = args["checkpoint"].as_str() {
+                    checkpoint::replay(&mut env, &checkpoint::load(cp.as_ref())?, |_, s| user.send(s.to_string()))?;
+                }
+                env.record_undo(Some(UndoConfig::default()));
+                self.env = Some(env);
+                self.user = Some(user);
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                json!({})
+            },
+            "setBreakpoints" => {
+                let count = args["breakpoints"].as_array().map(|b| b.len()).unwrap_or(0);
+                let unverified = json!({
+                    "verified": false,
+                    "message": "No source, use function or instruction breakpoints",
+                });
+                json!({"breakpoints": vec![unverified; count]})
+            },
+            "setFunctionBreakpoints" | "setInstructionBreakpoints" => {
+                let (key, function) = match command {
+                    "setFunctionBreakpoints" => ("name", true),
+                    _ => ("instructionReference", false),
+                };
+                let mut addrs = BTreeMap::new();
+                let mut breakpoints = vec![];
+                for b in args["breakpoints"].as_array().into_iter().flatten() {
+                    let offset = b["offset"].as_i64().unwrap_or(0);
+                    let addr = self.ann.parse_addr(b[key].as_str().unwrap_or(""))
+                        .and_then(|a| usize::try_from(a as i64 + offset).context("Address out of range"));
+                    let cond = b["condition"].as_str().filter(|c| !c.trim().is_empty()).map(str::parse::<Condition>).transpose();
+                    breakpoints.push(match addr.and_then(|a| Ok((a, cond?))) {
+                        Ok((a, cond)) => {
+                            addrs.insert(a, cond);
+                            json!({"verified": true, "instructionReference": a.to_string()})
+                        },
+                        Err(e) => json!({"verified": false, "message": e.to_string()}),
+                    });
+                }
+                match function {
+                    true => self.function_breakpoints = addrs,
+                    false => self.instruction_breakpoints = addrs,
+                }
+                json!({"breakpoints": breakpoints})
+            },
+            "configurationDone" => {
+                self.env()?;
+                json!({})
+            },
+            "threads" => json!({"threads": [{"id": THREAD_ID, "name": "vm"}]}),
+            "stackTrace" => {
+                let env = self.env()?;
+                let pc = env.curr_point.to_usize();
+                let mut addrs = vec![pc];
+                let returns = return_addresses(&env.memory, &env.stack);
+                addrs.extend(returns.iter().rev().map(|(_, a)| *a));
+                let frames: Vec<Value> = addrs.iter().enumerate()
+                    .map(|(i, a)| json!({
+                        "id": i,
+                        "name": self.frame_name(*a),
+                        "line": 0,
+                        "column": 0,
+                        "instructionPointerReference": a.to_string(),
+                    }))
+                    .collect();
+                json!({"stackFrames": frames, "totalFrames": addrs.len()})
+            },
+            "scopes" => json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false},
+                {"name": "Stack", "variablesReference": STACK_REF, "expensive": false},
+                {"name": "Memory", "variablesReference": MEMORY_REF, "expensive": false},
+            ]}),
+            "variables" => json!({"variables": self.variables(args["variablesReference"].as_u64().unwrap_or(0))?}),
+            "setVariable" => {
+                if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+                    bail!("Only registers can be set");
+                }
+                let name = args["name"].as_str().unwrap_or("");
+                let value: MemBlock = args["value"].as_str().unwrap_or("").trim().parse().context("Invalid value")?;
+                if value > 32767 {
+                    bail!("Value out of range: {}", value);
+                }
+                let env = self.env()?;
+                match name.parse::<Reg>() {
+                    Ok(r) => env.set_register(r.to_usize(), value),
+                    Err(_) if name == "pc" => env.set_pc(value.try_into()?),
+                    Err(_) => bail!("Unknown register: {}", name),
+                }
+                json!({"value": value.to_string()})
+            },
+            "readMemory" => {
+                let addr = self.ann.parse_addr(args["memoryReference"].as_str().unwrap_or(""))?;
+                let start = (addr * 2) as i64 + args["offset"].as_i64().unwrap_or(0);
+                let count = args["count"].as_u64().unwrap_or(0) as i64;
+                let env = self.env()?;
+                let end = (start + count).clamp(0, env.memory.len() as i64 * 2);
+                let start = start.clamp(0, end);
+                let bytes: Vec<u8> = (start..end)
+                    .map(|b| env.memory[b as usize / 2].to_le_bytes()[b as usize % 2])
+                    .collect();
+                json!({
+                    "address": (start / 2).to_string(),
+                    "data": base64(&bytes),
+                    "unreadableBytes": count - bytes.len() as i64,
+                })
+            },
+            "disassemble" => {
+                let addr = self.ann.parse_addr(args["memoryReference"].as_str().unwrap_or(""))?;
+                let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+                let count = args["instructionCount"].as_u64().unwrap_or(0) as usize;
+                json!({"instructions": self.disassemble(addr, offset, count)?})
+            },
+            "continue" => {
+                self.env()?;
+                self.running = Some(Run::Continue);
+                json!({"allThreadsContinued": true})
+            },
+            "next" => {
+                let env = self.env()?;
+                let pc = env.curr_point.to_usize();
+                if let Ok(Op::Call(_)) = decode_at(&env.memory, pc) {
+                    self.running = Some(Run::Next(pc + 2, env.stack.len()));
+                }
+                json!({})
+            },
+            "stepIn" => {
+                self.env()?;
+                json!({})
+            },
+            "stepOut" => {
+                let depth = self.env()?.stack.len();
+                self.running = Some(Run::StepOut(depth));
+                json!({})
+            },
+            "pause" => {
+                self.env()?;
+                json!({})
+            },
+            "stepBack" | "reverseContinue" => {
+                self.env()?;
+                json!({})
+            },
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                self.user.as_mut().context("No program launched")?.send(format!("{}\n", expression))?;
+                json!({"result": format!("sent {:?} to the game", expression), "variablesReference": 0})
+            },
+            "disconnect" | "terminate" => json!({}),
+            _ => bail!("Unsupported request: {}", command),
+        })
+    }
+
+    fn frame_name(&self, addr: usize) -> String {
+        let env = self.env.as_ref().unwrap();
+        match decode_at(&env.memory, addr) {
+            Ok(op) => format!("{}: {}", self.ann.symbol(addr), fmt_op(&op, self.ann)),
+            Err(_) => self.ann.symbol(addr),
+        }
+    }
+
+    fn variables(&mut self, reference: u64) -> anyhow::Result<Vec<Value>> {
+        let env = self.env.as_ref().context("No program launched")?;
+        let var = |name: String, value: MemBlock| json!({"name": name, "value": value.to_string(), "variablesReference": 0});
+        Ok(match reference {
+            REGISTERS_REF => {
+                let mut rv: Vec<Value> = env.registers.iter().enumerate()
+                    .map(|(i, r)| var(format!("r{}", i), *r))
+                    .collect();
+                rv.push(var("pc".to_string(), u16::from(env.curr_point)));
+                rv
+            },
+            STACK_REF => env.stack.iter().enumerate().rev()
+                .map(|(i, v)| var(format!("[{}]", i), *v))
+                .collect(),
+            MEMORY_REF => {
+                let pc = env.curr_point.to_usize();
+                let end = env.memory.len().min(pc + MEMORY_WORDS);
+                (pc..end)
+                    .map(|a| {
+                        let mut v = var(self.ann.symbol(a), env.memory[a]);
+                        v["memoryReference"] = json!(a.to_string());
+                        v
+                    })
+                    .collect()
+            },
+            _ => bail!("Unknown variables reference: {}", reference),
+        })
+    }
+
+    /// `count` instructions starting `offset` instructions away from `addr`. Going back
+    /// decodes forward from far enough before, which lines up on all but contrived code.
+    fn disassemble(&mut self, addr: usize, offset: i64, count: usize) -> anyhow::Result<Vec<Value>> {
+        let memory = &self.env.as_ref().context("No program launched")?.memory;
+        let start = match offset < 0 {
+            true => addr.saturating_sub(4 * offset.unsigned_abs() as usize),
+            false => addr,
+        };
+        // Addresses from `start`, one instruction or undecodable word each
+        let mut addrs = vec![];
+        let mut a = start;
+        let mut before = 0;
+        while a < memory.len() && (a < addr || addrs.len() < before + offset.max(0) as usize + count) {
+            if a < addr {
+                before += 1;
+            }
+            addrs.push(a);
+            let next = a + decode_at(&memory[..], a).map(|op| op.param_bytes() as usize).unwrap_or(1);
+            // Never step over `addr` itself
+            a = match a < addr && next > addr {
+                true => addr,
+                false => next,
+            };
+        }
+        // The first address is `offset` instructions from `addr`, negative ones may run off the start
+        let first = before as i64 + offset;
+        let mut rv = vec![];
+        for i in first..first + count as i64 {
+            let a = match usize::try_from(i).ok().and_then(|i| addrs.get(i)) {
+                Some(a) => *a,
+                None => {
+                    rv.push(json!({"address": "-1", "instruction": "", "presentationHint": "invalid"}));
+                    continue;
+                }
+            };
+            let (text, len) = match decode_at(&memory[..], a) {
+                Ok(op) => (fmt_op(&op, self.ann), op.param_bytes() as usize),
+                Err(_) => ("???".to_string(), 1),
+            };
+            let words: Vec<String> = memory[a..memory.len().min(a + len)].iter().map(|w| format!("{:04x}", w)).collect();
+            let mut v = json!({"address": a.to_string(), "instructionBytes": words.join(" "), "instruction": text});
+            if let Some(name) = self.ann.name(a) {
+                v["symbol"] = json!(name);
+            }
+            rv.push(v);
+        }
+        Ok(rv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let mut out = vec![];
+        write_message(&mut out, &json!({"seq": 1})).unwrap();
+        assert_eq!(out, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+        let mut input = &out[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({"seq": 1})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+}
@@ -0,0 +1,677 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Context};
+
+use crate::annotations::Annotations;
+use crate::checkpoint::{self, Line};
+use crate::condition::{breaks_at, Condition};
+use crate::hooks::{self, HookAction, HookId, Trigger};
+use crate::op_parser::*;
+use crate::patch::PatchSet;
+use crate::reverse_engineer::{decode_at, format_instruction, return_addresses};
+use crate::undo::UndoConfig;
+use crate::vm::{ExecutionEnv, Screen, StepOutcome};
+use crate::watch::{Access, Location, WatchId, WatchKind, WatchTarget};
+
+const HELP: &str = "\
+break [addr|label] [if <cond>]
+                      set a breakpoint, list them without an argument. Without an
+                      address, stop wherever the condition holds, e.g. `break if r7 != 0`
+delete [addr|label]   remove a breakpoint, all of them without an argument
+step [n]              execute n instructions (1)
+next                  step over a call
+finish                run until the current function returns
+advance <addr|label>  run until the pc reaches the address, ignoring breakpoints
+until <cond>          run until the condition holds, ignoring breakpoints
+continue              run until a breakpoint, input is needed or the program halts
+back [n]              undo n instructions or set/poke commands (1), game output stays
+rcontinue             go back to the last breakpoint hit, or as far as the history goes
+regs                  registers, pc and operation count
+stack                 stack from the top, return addresses marked
+x/N <addr|label>      N memory words (8)
+disas [addr|label]    instructions from the address (pc)
+set r<i> <val>        set a register
+poke <addr> <val>     write a memory word
+catch <in|out|op <name>|call <addr|label>>
+                      stop before matching instructions
+skip <addr|label>     don't execute the instruction at the address
+stub <addr|label> [r<i>=<val> ...]
+                      return from the function at the address with the registers set
+hooks                 list catches, skips and stubs
+unhook <n>            remove the hook listed as n
+watch <addr|label|r<i>> [val]
+                      stop after writes to the location, or only writes of val
+rwatch <addr|label|r<i>>
+                      stop after reads of the location
+watches               list watchpoints
+unwatch <n>           remove the watchpoint listed as n
+> <text>              send a line of game input
+quit
+";
+
+/// Instructions listed by `disas`
+const DISAS_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugCommand {
+    /// Address and condition, both missing lists the breakpoints
+    Break(Option<usize>, Option<Condition>),
+    Delete(Option<usize>),
+    Step(u64),
+    Next,
+    Finish,
+    Advance(usize),
+    Until(Condition),
+    Continue,
+    Back(usize),
+    ReverseContinue,
+    Regs,
+    Stack,
+    /// Word count and address
+    Examine(usize, usize),
+    Disas(Option<usize>),
+    SetReg(usize, MemBlock),
+    Poke(usize, MemBlock),
+    Catch(Trigger),
+    Skip(usize),
+    /// Function address and the registers it returns
+    Stub(usize, Vec<(usize, MemBlock)>),
+    Hooks,
+    /// Position in the `hooks` list, from 1
+    Unhook(usize),
+    Watch(WatchTarget, WatchKind),
+    Watches,
+    /// Position in the `watches` list, from 1
+    Unwatch(usize),
+    /// Line sent to the game, without the newline
+    Input(String),
+    Help,
+}
+
+/// A register or a single memory address
+fn parse_watch_target(s: &str, ann: &Annotations) -> anyhow::Result<WatchTarget> {
+    Ok(match s.parse::<Reg>() {
+        Ok(r) => WatchTarget::Reg(r.to_usize()),
+        Err(_) => {
+            let addr = ann.parse_addr(s)?;
+            WatchTarget::Mem(addr, addr)
+        },
+    })
+}
+
+fn parse_value(s: &str) -> anyhow::Result<MemBlock> {
+    let v: MemBlock = s.parse().with_context(|| format!("Invalid value: {}", s))?;
+    if v > 32767 {
+        bail!("Value out of range: {}", v);
+    }
+    Ok(v)
+}
+
+impl DebugCommand {
+    pub fn parse(line: &str, ann: &Annotations) -> anyhow::Result<Self> {
+        if let Some(text) = line.strip_prefix('>') {
+            return Ok(Self::Input(text.trim().to_string()));
+        }
+        if let Some(cond) = line.strip_prefix("until ") {
+            return Ok(Self::Until(cond.parse()?));
+        }
+        if let Some((start, cond)) = line.split_once(" if ") {
+            let cond: Condition = cond.parse()?;
+            return match start.split_whitespace().collect::<Vec<_>>()[..] {
+                ["break" | "b"] => Ok(Self::Break(None, Some(cond))),
+                ["break" | "b", addr] => Ok(Self::Break(Some(ann.parse_addr(addr)?), Some(cond))),
+                _ => bail!(">> Usage: break [addr|label] [if <cond>]"),
+            };
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => bail!("Empty command"),
+        };
+        if let Some(count) = cmd.strip_prefix("x") {
+            let count = match count.strip_prefix('/') {
+                Some(n) => n.parse().with_context(|| format!("Invalid count: {}", n))?,
+                None if count.is_empty() => 8,
+                None => bail!("Unknown command: {}", cmd),
+            };
+            return match args {
+                [addr] => Ok(Self::Examine(count, ann.parse_addr(addr)?)),
+                _ => bail!(">> Usage: x/N <addr|label>"),
+            };
+        }
+        Ok(match (cmd, args) {
+            ("break" | "b", []) => Self::Break(None, None),
+            ("break" | "b", [addr]) => Self::Break(Some(ann.parse_addr(addr)?), None),
+            ("delete" | "d", []) => Self::Delete(None),
+            ("delete" | "d", [addr]) => Self::Delete(Some(ann.parse_addr(addr)?)),
+            ("step" | "s", []) => Self::Step(1),
+            ("step" | "s", [n]) => Self::Step(n.parse().with_context(|| format!("Invalid count: {}", n))?),
+            ("next" | "n", []) => Self::Next,
+            ("finish", []) => Self::Finish,
+            ("advance", [addr]) => Self::Advance(ann.parse_addr(addr)?),
+            ("continue" | "c", []) => Self::Continue,
+            ("back", []) => Self::Back(1),
+            ("back", [n]) => Self::Back(n.parse().with_context(|| format!("Invalid count: {}", n))?),
+            ("rcontinue", []) => Self::ReverseContinue,
+            ("regs", []) => Self::Regs,
+            ("stack", []) => Self::Stack,
+            ("disas", []) => Self::Disas(None),
+            ("disas", [addr]) => Self::Disas(Some(ann.parse_addr(addr)?)),
+            ("set", [reg, val]) => {
+                let reg: Reg = reg.parse().with_context(|| format!("Invalid register: {}", reg))?;
+                Self::SetReg(reg.to_usize(), parse_value(val)?)
+            },
+            ("poke", [addr, val]) => Self::Poke(ann.parse_addr(addr)?, parse_value(val)?),
+            ("catch", ["in"]) => Self::Catch(Trigger::Input),
+            ("catch", ["out"]) => Self::Catch(Trigger::Output),
+            ("catch", ["op", name]) => match OPCODES.iter().position(|(n, _)| n == name) {
+                Some(code) => Self::Catch(Trigger::Opcode(code as u16)),
+                None => bail!("Unknown instruction: {}", name),
+            },
+            ("catch", ["call", addr]) => Self::Catch(Trigger::Call(ann.parse_addr(addr)?)),
+            ("skip", [addr]) => Self::Skip(ann.parse_addr(addr)?),
+            ("stub", [addr, regs @ ..]) => {
+                let mut registers = vec![];
+                for r in regs.iter() {
+                    let (reg, val) = r.split_once('=').with_context(|| format!("Expected r<i>=<val>: {}", r))?;
+                    let reg: Reg = reg.parse()?;
+                    registers.push((reg.to_usize(), parse_value(val)?));
+                }
+                Self::Stub(ann.parse_addr(addr)?, registers)
+            },
+            ("hooks", []) => Self::Hooks,
+            ("unhook", [n]) => Self::Unhook(n.parse().with_context(|| format!("Invalid number: {}", n))?),
+            ("watch", [target]) => Self::Watch(parse_watch_target(target, ann)?, WatchKind::Write),
+            ("watch", [target, val]) => Self::Watch(parse_watch_target(target, ann)?, WatchKind::Equals(parse_value(val)?)),
+            ("rwatch", [target]) => Self::Watch(parse_watch_target(target, ann)?, WatchKind::Read),
+            ("watches", []) => Self::Watches,
+            ("unwatch", [n]) => Self::Unwatch(n.parse().with_context(|| format!("Invalid number: {}", n))?),
+            ("help" | "h", []) => Self::Help,
+            ("break" | "b" | "delete" | "d" | "step" | "s" | "next" | "n" | "finish" | "advance" | "until" | "continue" | "c" | "back" | "rcontinue"
+                | "regs" | "stack" | "disas" | "set" | "poke" | "catch" | "skip" | "stub" | "hooks" | "unhook"
+                | "watch" | "rwatch" | "watches" | "unwatch" | "help" | "h", _) => {
+                bail!(">> Usage: {}", HELP.lines().find(|l| l.starts_with(cmd)).unwrap_or(cmd))
+            },
+            _ => bail!("Unknown command: {}, try `help`", cmd),
+        })
+    }
+}
+
+/// Pauses an `ExecutionEnv` between commands. Game I/O goes through the `Screen`:
+/// output is shown after every command that runs the program, input is sent with `> text`.
+pub struct Debugger<'a> {
+    pub env: ExecutionEnv,
+    user: Screen,
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    /// Conditions checked after every instruction
+    conditions: Vec<Condition>,
+    /// Hooks added by `catch`, `skip` and `stub`, with the command that added them
+    hooks: Vec<(HookId, String)>,
+    /// Watchpoints added by `watch` and `rwatch`, with the command that added them
+    watches: Vec<(WatchId, String)>,
+    ann: &'a Annotations,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(content: &[u8], register_preset: Option<u16>, patches: &PatchSet, ann: &'a Annotations) -> anyhow::Result<Self> {
+        let (screen, user) = Screen::create();
+        let mut env = ExecutionEnv::with_patches(content, screen, register_preset, patches)?;
+        env.record_undo(Some(UndoConfig::default()));
+        Ok(Self {
+            env,
+            user,
+            breakpoints: BTreeMap::new(),
+            conditions: vec![],
+            hooks: vec![],
+            watches: vec![],
+            ann,
+        })
+    }
+
+    /// Replay a checkpoint, see `checkpoint::replay`: its input after the last runtime
+    /// write is queued for the session
+    pub fn replay(&mut self, lines: &[Line]) -> anyhow::Result<()> {
+        let user = &mut self.user;
+        checkpoint::replay(&mut self.env, lines, |_, s| user.send(s.to_string()))
+    }
+
+    fn pc(&self) -> usize {
+        self.env.curr_point.to_usize()
+    }
+
+    fn format_at(&self, addr: usize) -> String {
+        let memory = &self.env.memory[..];
+        match decode_at(memory, addr) {
+            Ok(op) => {
+                let end = memory.len().min(addr + op.param_bytes() as usize);
+                format_instruction(addr, &memory[addr..end], &op, self.ann)
+            },
+            Err(e) => format!("{:>5}: {}", addr, e),
+        }
+    }
+
+    /// The instruction about to execute
+    pub fn location(&self) -> String {
+        format!("=> {}\n", self.format_at(self.pc()))
+    }
+
+    /// Run until `done` holds, a breakpoint is reached or the program can't go on,
+    /// then report the game output, why it stopped and where
+    fn resume(&mut self, mut done: impl FnMut(&ExecutionEnv, &StepOutcome) -> bool) -> anyhow::Result<String> {
+        let (breakpoints, conditions) = (&self.breakpoints, &self.conditions);
+        let step = self.env.run_until(|env, step| {
+            done(env, step) || breaks_at(breakpoints, env) || conditions.iter().any(|c| c.eval(env))
+        })?;
+        self.report(&step)
+    }
+
+    /// The game output, why execution stopped after `step` and where
+    fn report(&mut self, step: &StepOutcome) -> anyhow::Result<String> {
+        let mut rv = self.user.get_all()?;
+        if !rv.is_empty() && !rv.ends_with('\n') {
+            rv.push('\n');
+        }
+        if step.halted {
+            rv += "Program halted\n";
+        } else if step.waiting_for_input {
+            rv += "Waiting for input, send it with `> text`\n";
+        } else if step.stopped {
+            let hits = self.env.watches.take_hits();
+            if hits.is_empty() {
+                rv += "Stopped by a hook\n";
+            }
+            for hit in hits {
+                let n = self.watches.iter().position(|(id, _)| *id == hit.id).map_or(0, |i| i + 1);
+                let location = match hit.location {
+                    Location::Mem(a) => self.ann.symbol(a),
+                    Location::Reg(r) => format!("r{}", r),
+                };
+                let access = match hit.access {
+                    Access::Read => "read",
+                    Access::Write => "wrote",
+                };
+                writeln!(rv, "Watchpoint {}: {} {} {} = {}", n, self.ann.symbol(hit.pc), access, location, hit.value).unwrap();
+            }
+        } else if breaks_at(&self.breakpoints, &self.env) {
+            writeln!(rv, "Breakpoint at {}", self.ann.symbol(step.pc_after)).unwrap();
+        } else if let Some(c) = self.conditions.iter().find(|c| c.eval(&self.env)) {
+            writeln!(rv, "Condition `{}` holds", c).unwrap();
+        }
+        rv += &self.location();
+        Ok(rv)
+    }
+
+    pub fn execute(&mut self, cmd: &DebugCommand) -> anyhow::Result<String> {
+        let mut rv = String::new();
+        match cmd {
+            DebugCommand::Break(None, None) => {
+                for (b, cond) in self.breakpoints.iter() {
+                    write!(rv, "{}", self.format_at(*b)).unwrap();
+                    if let Some(c) = cond {
+                        write!(rv, "\tif {}", c).unwrap();
+                    }
+                    rv.push('\n');
+                }
+                for c in self.conditions.iter() {
+                    writeln!(rv, "if {}", c).unwrap();
+                }
+            },
+            DebugCommand::Break(None, Some(cond)) => {
+                self.conditions.push(cond.clone());
+                writeln!(rv, "Stopping when `{}` holds", cond).unwrap();
+            },
+            DebugCommand::Break(Some(addr), cond) => {
+                self.breakpoints.insert(*addr, cond.clone());
+                writeln!(rv, "Breakpoint at {}", self.ann.symbol(*addr)).unwrap();
+            },
+            DebugCommand::Delete(None) => {
+                self.breakpoints.clear();
+                self.conditions.clear();
+            },
+            DebugCommand::Delete(Some(addr)) => {
+                if self.breakpoints.remove(addr).is_none() {
+                    bail!("No breakpoint at {}", addr);
+                }
+            },
+            DebugCommand::Step(n) => {
+                let mut count = 0;
+                return self.resume(|_, _| {
+                    count += 1;
+                    count >= *n
+                });
+            },
+            DebugCommand::Next => {
+                let pc = self.pc();
+                if let Ok(Op::Call(_)) = decode_at(&self.env.memory, pc) {
+                    // Back at the return address with the same stack, recursion included
+                    let depth = self.env.stack.len();
+                    return self.resume(|env, step| step.pc_after == pc + 2 && env.stack.len() == depth);
+                }
+                return self.execute(&DebugCommand::Step(1));
+            },
+            DebugCommand::Finish => {
+                // The `ret` that pops the return address pushed before we got here
+                let depth = self.env.stack.len();
+                return self.resume(|env, step| matches!(step.op, Op::Ret) && env.stack.len() < depth);
+            },
+            DebugCommand::Advance(addr) => {
+                let step = self.env.run_until_pc(*addr, |_, _| {})?;
+                return self.report(&step);
+            },
+            DebugCommand::Until(cond) => {
+                let step = self.env.run_until_expr(cond)?;
+                return self.report(&step);
+            },
+            DebugCommand::Continue => return self.resume(|_, _| false),
+            DebugCommand::Back(n) => {
+                if self.env.undo.as_ref().is_none_or(|u| u.is_empty()) {
+                    bail!("No history to go back in");
+                }
+                let count = self.env.step_back(*n);
+                writeln!(rv, "Went back {} steps", count).unwrap();
+                rv += &self.location();
+            },
+            DebugCommand::ReverseContinue => {
+                let (breakpoints, conditions) = (&self.breakpoints, &self.conditions);
+                let hit = self.env.run_back_until(|env| breaks_at(breakpoints, env) || conditions.iter().any(|c| c.eval(env)));
+                rv += match hit {
+                    true => "Stopped going back\n",
+                    false => "Reached the start of the history\n",
+                };
+                rv += &self.location();
+            },
+            DebugCommand::Regs => {
+                let regs: Vec<String> = self.env.registers.iter().enumerate()
+                    .map(|(i, r)| format!("r{}={}", i, r))
+                    .collect();
+                writeln!(rv, "{}", regs.join(" ")).unwrap();
+                writeln!(rv, "pc={} ops={}", self.ann.symbol(self.pc()), self.env.operation_count).unwrap();
+            },
+            DebugCommand::Stack => {
+                let returns = return_addresses(&self.env.memory, &self.env.stack);
+                for (i, v) in self.env.stack.iter().enumerate().rev() {
+                    write!(rv, "stack[{}] = {}", i, v).unwrap();
+                    if returns.iter().any(|(r, _)| *r == i) {
+                        write!(rv, "\t; return to {}", self.ann.symbol(*v as usize)).unwrap();
+                    }
+                    rv.push('\n');
+                }
+            },
+            DebugCommand::Examine(count, addr) => {
+                let end = addr.saturating_add(*count).min(self.env.memory.len());
+                for (i, row) in self.env.memory[*addr..end].chunks(8).enumerate() {
+                    let words: Vec<String> = row.iter().map(|w| format!("{:>5}", w)).collect();
+                    writeln!(rv, "{:>5}: {}", addr + i * 8, words.join(" ")).unwrap();
+                }
+            },
+            DebugCommand::Disas(addr) => {
+                let mut addr = addr.unwrap_or(self.pc());
+                for _ in 0..DISAS_LEN {
+                    if addr >= self.env.memory.len() {
+                        break;
+                    }
+                    if let Some(name) = self.ann.name(addr) {
+                        writeln!(rv, "{}:", name).unwrap();
+                    }
+                    let mark = match (addr == self.pc(), self.breakpoints.contains_key(&addr)) {
+                        (true, _) => "=>",
+                        (false, true) => "* ",
+                        (false, false) => "  ",
+                    };
+                    writeln!(rv, "{} {}", mark, self.format_at(addr)).unwrap();
+                    addr += decode_at(&self.env.memory, addr).map(|op| op.param_bytes() as usize).unwrap_or(1);
+                }
+            },
+            DebugCommand::SetReg(reg, val) => self.env.set_register(*reg, *val),
+            DebugCommand::Poke(addr, val) => self.env.poke((*addr as u16).try_into().unwrap(), *val),
+            DebugCommand::Catch(trigger) => {
+                let id = self.env.hooks.add(*trigger, Box::new(|_, _| HookAction::Stop));
+                self.hooks.push((id, format!("catch {}", trigger)));
+            },
+            DebugCommand::Skip(addr) => {
+                let id = self.env.hooks.add(Trigger::Addr(*addr), Box::new(|_, _| HookAction::Skip));
+                self.hooks.push((id, format!("skip {}", self.ann.symbol(*addr))));
+            },
+            DebugCommand::Stub(addr, registers) => {
+                let id = self.env.hooks.add(Trigger::Addr(*addr), hooks::stub(registers.clone()));
+                let regs: String = registers.iter().map(|(r, v)| format!(" r{}={}", r, v)).collect();
+                self.hooks.push((id, format!("stub {}{}", self.ann.symbol(*addr), regs)));
+            },
+            DebugCommand::Hooks => {
+                for (i, (_, text)) in self.hooks.iter().enumerate() {
+                    writeln!(rv, "{}: {}", i + 1, text).unwrap();
+                }
+            },
+            DebugCommand::Unhook(n) => {
+                if *n == 0 || *n > self.hooks.len() {
+                    bail!("No hook {}, see `hooks`", n);
+                }
+                let (id, _) = self.hooks.remove(n - 1);
+                self.env.hooks.remove(id);
+            },
+            DebugCommand::Watch(target, kind) => {
+                let id = self.env.watches.add(*target, *kind);
+                let location = match target {
+                    WatchTarget::Mem(a, _) => self.ann.symbol(*a),
+                    WatchTarget::Reg(r) => format!("r{}", r),
+                };
+                let text = match kind {
+                    WatchKind::Read => format!("rwatch {}", location),
+                    WatchKind::Write => format!("watch {}", location),
+                    WatchKind::Equals(v) => format!("watch {} {}", location, v),
+                };
+                self.watches.push((id, text));
+            },
+            DebugCommand::Watches => {
+                for (i, (_, text)) in self.watches.iter().enumerate() {
+                    writeln!(rv, "{}: {}", i + 1, text).unwrap();
+                }
+            },
+            DebugCommand::Unwatch(n) => {
+                if *n == 0 || *n > self.watches.len() {
+                    bail!("No watchpoint {}, see `watches`", n);
+                }
+                let (id, _) = self.watches.remove(n - 1);
+                self.env.watches.remove(id);
+            },
+            DebugCommand::Input(text) => self.user.send(format!("{}\n", text))?,
+            DebugCommand::Help => rv += HELP,
+        }
+        Ok(rv)
+    }
+
+    /// Read commands from `input` until it ends or `quit`. An empty line repeats the last command
+    pub fn repl(&mut self, input: impl BufRead, out: &mut impl Write) -> anyhow::Result<()> {
+        write!(out, "{}(sdb) ", self.location())?;
+        out.flush()?;
+        let mut last = None;
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line == "quit" || line == "q" {
+                break;
+            }
+            let cmd = match (line.is_empty(), &last) {
+                (true, Some(cmd)) => Ok(Clone::clone(cmd)),
+                _ => DebugCommand::parse(line, self.ann),
+            };
+            match cmd.and_then(|cmd| {
+                let rv = self.execute(&cmd);
+                last = Some(cmd);
+                rv
+            }) {
+                Ok(text) => write!(out, "{}", text)?,
+                Err(e) => writeln!(out, ">> ERROR: {}", e)?,
+            }
+            write!(out, "(sdb) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+
+    const PROGRAM: &str = "
+        set r0 2
+        call double
+        out r0
+        in r1
+        halt
+        double: push r1
+        add r0 r0 r0
+        pop r1
+        ret
+    ";
+
+    #[test]
+    fn test_parse() {
+        let ann = Annotations::from_json(r#"{"10": "double"}"#).unwrap();
+        let parse = |s| DebugCommand::parse(s, &ann);
+        assert_eq!(parse("break double").unwrap(), DebugCommand::Break(Some(10), None));
+        assert_eq!(parse("break double if r0 > 5").unwrap(), DebugCommand::Break(Some(10), Some("r0 > 5".parse().unwrap())));
+        assert!(matches!(parse("b if ops >= 3").unwrap(), DebugCommand::Break(None, Some(_))));
+        assert!(parse("break if r9").is_err());
+        assert_eq!(parse("x/4 7").unwrap(), DebugCommand::Examine(4, 7));
+        assert_eq!(parse("set r7 25734").unwrap(), DebugCommand::SetReg(7, 25734));
+        assert_eq!(parse("> go north").unwrap(), DebugCommand::Input("go north".into()));
+        assert!(parse("poke 10 32768").is_err());
+        assert!(parse("break nowhere").is_err());
+        assert!(parse("step a").is_err());
+        assert!(parse("jump 4").is_err());
+        assert_eq!(parse("catch op wmem").unwrap(), DebugCommand::Catch(Trigger::Opcode(16)));
+        assert_eq!(parse("stub double r0=6 r1=4").unwrap(), DebugCommand::Stub(10, vec![(0, 6), (1, 4)]));
+        assert!(parse("stub double r0").is_err());
+        assert!(parse("catch op jump").is_err());
+        assert_eq!(parse("watch double 5").unwrap(), DebugCommand::Watch(WatchTarget::Mem(10, 10), WatchKind::Equals(5)));
+        assert_eq!(parse("rwatch r7").unwrap(), DebugCommand::Watch(WatchTarget::Reg(7), WatchKind::Read));
+        assert!(parse("watch r7 32768").is_err());
+        assert_eq!(parse("until ops > 5 && r0").unwrap(), DebugCommand::Until("ops > 5 && r0".parse().unwrap()));
+        assert!(parse("until r9").is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let ann = Annotations::from_json(r#"{"10": "double"}"#).unwrap();
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        let mut run = |cmd: &str| {
+            let cmd = DebugCommand::parse(cmd, &ann).unwrap();
+            dbg.execute(&cmd).unwrap()
+        };
+        assert!(run("step").ends_with("=>     3: 0011 000a            call double\n"));
+        assert!(run("next").contains("out r0"));
+        assert!(run("regs").starts_with("r0=4 r1=0"));
+
+        // Through `double` without stopping at its breakpoint
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        dbg.execute(&DebugCommand::Break(Some(12), None)).unwrap();
+        assert_eq!(dbg.execute(&DebugCommand::Advance(5)).unwrap(), "=>     5: 0013 8000            out r0\n");
+        assert_eq!(dbg.env.registers[0], 4);
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        dbg.execute(&DebugCommand::Break(Some(12), None)).unwrap();
+        let until = DebugCommand::parse("until r0 == 4", &ann).unwrap();
+        assert_eq!(dbg.execute(&until).unwrap(), "=>    16: 0003 8001            pop r1\n");
+
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        dbg.execute(&DebugCommand::Break(Some(10), None)).unwrap();
+        assert!(dbg.execute(&DebugCommand::Continue).unwrap().starts_with("Breakpoint at double\n"));
+        assert!(dbg.execute(&DebugCommand::Stack).unwrap().starts_with("stack[0] = 5\t; return to 5"));
+        dbg.execute(&DebugCommand::SetReg(0, 30)).unwrap();
+        assert!(dbg.execute(&DebugCommand::Finish).unwrap().ends_with("out r0\n"));
+        assert_eq!(dbg.env.registers[0], 60);
+        // `out` then waiting at the `in`
+        assert_eq!(dbg.execute(&DebugCommand::Continue).unwrap(), "<\nWaiting for input, send it with `> text`\n=>     7: 0014 8001            in r1\n");
+        dbg.execute(&DebugCommand::Input("x".into())).unwrap();
+        dbg.execute(&DebugCommand::Delete(Some(10))).unwrap();
+        // The `halt` becomes a `noop`, the program ends on the `ret` with an empty stack
+        dbg.execute(&DebugCommand::Poke(9, 21)).unwrap();
+        assert!(dbg.execute(&DebugCommand::Continue).unwrap().starts_with("Program halted"));
+
+        // Back from the final `ret` into the second run of `double`
+        assert!(dbg.execute(&DebugCommand::Back(1)).unwrap().starts_with("Went back 1 steps\n"));
+        dbg.execute(&DebugCommand::Break(Some(12), None)).unwrap();
+        assert!(dbg.execute(&DebugCommand::ReverseContinue).unwrap().ends_with("add r0 r0 r0\n"));
+        assert_eq!((dbg.env.registers[0], dbg.env.memory[9]), (60, 21));
+        // Going further back undoes the poke too
+        dbg.execute(&DebugCommand::Delete(None)).unwrap();
+        assert!(dbg.execute(&DebugCommand::ReverseContinue).unwrap().starts_with("Reached the start"));
+        assert_eq!((dbg.env.operation_count, dbg.env.memory[9]), (0, 0));
+
+        // A `push` + `ret` jump inside the function doesn't end it
+        let program = "call f\n out r0\n halt\n f: push tail\n ret\n tail: set r0 65\n ret";
+        let mut dbg = Debugger::new(&to_bytes(&assemble(program).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        dbg.execute(&DebugCommand::Break(Some(5), None)).unwrap();
+        dbg.execute(&DebugCommand::Continue).unwrap();
+        assert!(dbg.execute(&DebugCommand::Finish).unwrap().ends_with("out r0\n"));
+        assert_eq!(dbg.env.registers[0], 65);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let ann = Annotations::from_json(r#"{"10": "double"}"#).unwrap();
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        let mut run = |cmd: &str| {
+            let cmd = DebugCommand::parse(cmd, &ann).unwrap();
+            dbg.execute(&cmd)
+        };
+        run("catch call double").unwrap();
+        assert_eq!(run("continue").unwrap(), "Stopped by a hook\n=>     3: 0011 000a            call double\n");
+        run("stub double r0=66").unwrap();
+        run("catch out").unwrap();
+        assert!(run("continue").unwrap().ends_with("out r0\n"));
+        assert_eq!(run("hooks").unwrap(), "1: catch call 10\n2: stub double r0=66\n3: catch out\n");
+        run("unhook 3").unwrap();
+        assert!(run("unhook 3").is_err());
+        assert!(run("continue").unwrap().starts_with("B\nWaiting for input"));
+        assert_eq!(dbg.env.registers[0], 66);
+        assert!(dbg.env.stack.is_empty());
+
+        // The skipped `out` neither prints nor counts
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        dbg.execute(&DebugCommand::Skip(5)).unwrap();
+        assert!(dbg.execute(&DebugCommand::Continue).unwrap().starts_with("Waiting for input"));
+        assert_eq!(dbg.env.operation_count, 6);
+    }
+
+    #[test]
+    fn test_watches() {
+        let ann = Annotations::from_json(r#"{"10": "double"}"#).unwrap();
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        let mut run = |cmd: &str| {
+            let cmd = DebugCommand::parse(cmd, &ann).unwrap();
+            dbg.execute(&cmd)
+        };
+        run("watch r0 4").unwrap();
+        run("rwatch r0").unwrap();
+        assert_eq!(run("watches").unwrap(), "1: watch r0 4\n2: rwatch r0\n");
+        // `add r0 r0 r0` reads r0 twice, `set r0 2` before it wrote another value
+        assert_eq!(run("continue").unwrap(), "\
+            Watchpoint 2: 12 read r0 = 2\n\
+            Watchpoint 2: 12 read r0 = 2\n\
+            Watchpoint 1: 12 wrote r0 = 4\n\
+            =>    16: 0003 8001            pop r1\n");
+        assert!(run("unwatch 3").is_err());
+        run("unwatch 2").unwrap();
+        assert!(run("continue").unwrap().contains("Waiting for input"));
+    }
+
+    #[test]
+    fn test_repl() {
+        let ann = Annotations::default();
+        let mut dbg = Debugger::new(&to_bytes(&assemble(PROGRAM).unwrap()), None, &PatchSet::default(), &ann).unwrap();
+        let mut out = vec![];
+        dbg.repl("step\n\nbogus\ndisas 0\nquit\nstep\n".as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(dbg.env.operation_count, 2);
+        assert!(out.contains(">> ERROR: Unknown command: bogus"));
+        assert!(out.contains("set r0 2\n       3: 0011 000a            call 10\n"));
+        assert!(out.contains("=>    10: 0002 8001            push r1\n"));
+        // Counts running past the end of memory stop there
+        assert_eq!(dbg.execute(&DebugCommand::Examine(usize::MAX, 32767)).unwrap(), "32767:     0\n");
+    }
+}
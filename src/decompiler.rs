@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::bail;
+
+use crate::annotations::Annotations;
+use crate::cfg::{Cfg, EdgeKind};
+use crate::op_parser::*;
+
+/// Structured pseudo-code for one function.
+///
+/// Conditionals are closed at the immediate post-dominator of the branch, loops
+/// are found through back edges and anything left unstructured becomes a `goto`.
+/// Values pushed inside the function are shown as `localN`.
+pub struct Decompiler<'a> {
+	cfg: &'a Cfg,
+	ann: &'a Annotations,
+	ipdom: BTreeMap<usize, Option<usize>>,
+	/// Loop header and the block control continues with after the loop
+	headers: BTreeMap<usize, Option<usize>>,
+	depth: BTreeMap<usize, Option<usize>>,
+	lines: Vec<String>,
+	emitted: BTreeMap<usize, usize>,
+	labels: BTreeSet<usize>,
+	indent: usize,
+}
+
+struct Loop {
+	header: usize,
+	exit: Option<usize>,
+}
+
+fn post_dominators(cfg: &Cfg) -> BTreeMap<usize, BTreeSet<usize>> {
+	let all: BTreeSet<usize> = cfg.blocks.keys().cloned().collect();
+	let mut pdom: BTreeMap<usize, BTreeSet<usize>> = cfg.blocks.keys()
+		.map(|b| (*b, if cfg.blocks[b].succs.is_empty() { BTreeSet::from([*b]) } else { all.clone() }))
+		.collect();
+	let mut changed = true;
+	while changed {
+		changed = false;
+		for (b, block) in cfg.blocks.iter().rev() {
+			let mut succs = block.succs.iter().map(|(s, _)| &pdom[s]);
+			let mut set = match succs.next() {
+				None => continue,
+				Some(first) => first.clone(),
+			};
+			for s in succs {
+				set = set.intersection(s).cloned().collect();
+			}
+			set.insert(*b);
+			if set != pdom[b] {
+				pdom.insert(*b, set);
+				changed = true;
+			}
+		}
+	}
+	pdom
+}
+
+/// Closest strict post-dominator of every block, `None` when paths only meet at function exit
+fn immediate_post_dominators(cfg: &Cfg) -> BTreeMap<usize, Option<usize>> {
+	let pdom = post_dominators(cfg);
+	pdom.iter()
+		.map(|(b, set)| {
+			let ipdom = set.iter()
+				.filter(|p| *p != b)
+				.max_by_key(|p| pdom[p].len())
+				.cloned();
+			(*b, ipdom)
+		})
+		.collect()
+}
+
+/// Back edges found by a depth-first walk from the entry, keyed by loop header
+/// with the blocks jumping back to it
+fn loop_headers(cfg: &Cfg) -> BTreeMap<usize, Vec<usize>> {
+	let mut headers: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+	let mut on_stack = BTreeSet::new();
+	let mut visited = BTreeSet::new();
+	// (block, next successor index)
+	let mut stack = vec![(cfg.entry, 0)];
+	on_stack.insert(cfg.entry);
+	visited.insert(cfg.entry);
+	while let Some((b, i)) = stack.pop() {
+		let succs = &cfg.blocks[&b].succs;
+		if i >= succs.len() {
+			on_stack.remove(&b);
+			continue;
+		}
+		stack.push((b, i + 1));
+		let s = succs[i].0;
+		if on_stack.contains(&s) {
+			headers.entry(s).or_default().push(b);
+		} else if visited.insert(s) {
+			on_stack.insert(s);
+			stack.push((s, 0));
+		}
+	}
+	headers
+}
+
+/// Blocks of the natural loop of `header`: those reaching a latch without passing the header
+fn loop_body(cfg: &Cfg, header: usize, latches: &[usize]) -> BTreeSet<usize> {
+	let mut body = BTreeSet::from([header]);
+	let mut pending = latches.to_vec();
+	while let Some(b) = pending.pop() {
+		if !body.insert(b) {
+			continue;
+		}
+		for (p, block) in cfg.blocks.iter() {
+			if block.succs.iter().any(|(s, _)| *s == b) {
+				pending.push(*p);
+			}
+		}
+	}
+	body
+}
+
+/// Stack depth (relative to function entry) at the start of every block,
+/// `None` where paths disagree about it (e.g. pushing inside a loop)
+fn stack_depths(cfg: &Cfg) -> BTreeMap<usize, Option<usize>> {
+	let mut depth: BTreeMap<usize, Option<usize>> = BTreeMap::from([(cfg.entry, Some(0))]);
+	let mut pending = vec![cfg.entry];
+	while let Some(b) = pending.pop() {
+		let block = &cfg.blocks[&b];
+		let mut d = depth[&b];
+		for (_, op) in block.ops.iter() {
+			match op {
+				Op::Push(_) => d = d.map(|d| d + 1),
+				Op::Pop(_) => d = d.map(|d| d.saturating_sub(1)),
+				_ => {}
+			}
+		}
+		for (s, _) in block.succs.iter() {
+			match depth.get(s) {
+				None => {
+					depth.insert(*s, d);
+					pending.push(*s);
+				},
+				Some(prev) if *prev != d && prev.is_some() => {
+					depth.insert(*s, None);
+					pending.push(*s);
+				},
+				_ => {}
+			}
+		}
+	}
+	depth
+}
+
+fn fmt_dest(a: Addr) -> String {
+	match a {
+		Addr::Reg(r) => r.to_string(),
+		Addr::Mem(m) => format!("mem[{}]", u16::from(m)),
+	}
+}
+
+fn fmt_binary(dest: Addr, a: Val, sym: &str, b: Val) -> String {
+	format!("{} = {} {} {}", fmt_dest(dest), a, sym, b)
+}
+
+fn is_minus_one(v: Val) -> bool {
+	matches!(v, Val::Num(n) if u16::from(n) == 32767)
+}
+
+/// Condition under which a `jt`/`jf` jumps, or the opposite one when `negate`
+fn fmt_cond(op: &Op, negate: bool) -> String {
+	let (v, zero) = match *op {
+		Op::Jt(v, _) => (v, false),
+		Op::Jf(v, _) => (v, true),
+		_ => unreachable!("not a conditional jump"),
+	};
+	let sym = if zero != negate { "==" } else { "!=" };
+	format!("{} {} 0", v, sym)
+}
+
+impl<'a> Decompiler<'a> {
+	/// Fails when nothing decodes at the entry, both analyses start from its block
+	pub fn new(cfg: &'a Cfg, ann: &'a Annotations) -> anyhow::Result<Self> {
+		if !cfg.blocks.contains_key(&cfg.entry) {
+			bail!("No code at {}", cfg.entry);
+		}
+		let mut rv = Self {
+			cfg,
+			ann,
+			ipdom: immediate_post_dominators(cfg),
+			headers: BTreeMap::new(),
+			depth: stack_depths(cfg),
+			lines: vec![],
+			emitted: BTreeMap::new(),
+			labels: BTreeSet::new(),
+			indent: 1,
+		};
+		// Loop exit is the first post-dominator of the header outside the loop
+		for (header, latches) in loop_headers(cfg) {
+			let body = loop_body(cfg, header, &latches);
+			let mut exit = rv.ipdom[&header];
+			while let Some(e) = exit.filter(|e| body.contains(e)) {
+				exit = rv.ipdom[&e];
+			}
+			rv.headers.insert(header, exit);
+		}
+		Ok(rv)
+	}
+
+	fn fn_name(&self, addr: usize) -> String {
+		match self.ann.name(addr) {
+			Some(n) => n.to_string(),
+			None => format!("fn_{}", addr),
+		}
+	}
+
+	fn line(&mut self, s: String) {
+		self.lines.push(format!("{}{}", "    ".repeat(self.indent), s));
+	}
+
+	/// Pseudo-code statement for a non-branching op. `depth` is the local stack depth,
+	/// pushes and pops stay explicit when it is unknown
+	fn statement(&mut self, op: &Op, depth: &mut Option<usize>) {
+		let s = match *op {
+			Op::Noop => return,
+			Op::Set(r, v) => format!("{} = {}", r, v),
+			Op::Push(v) => match depth {
+				Some(d) => {
+					*d += 1;
+					format!("local{} = {}", *d - 1, v)
+				},
+				None => format!("push({})", v),
+			},
+			Op::Pop(a) => match depth {
+				Some(d) if *d > 0 => {
+					*d -= 1;
+					format!("{} = local{}", fmt_dest(a), *d)
+				},
+				_ => format!("{} = pop()", fmt_dest(a)),
+			},
+			Op::Eq(a, b, c) => fmt_binary(a, b, "==", c),
+			Op::Gt(a, b, c) => fmt_binary(a, b, ">", c),
+			Op::Add(a, b, c) if is_minus_one(c) => format!("{} = {} - 1", fmt_dest(a), b),
+			Op::Add(a, b, c) => fmt_binary(a, b, "+", c),
+			Op::Mult(a, b, c) => fmt_binary(a, b, "*", c),
+			Op::Mod(a, b, c) => fmt_binary(a, b, "%", c),
+			Op::And(a, b, c) => fmt_binary(a, b, "&", c),
+			Op::Or(a, b, c) => fmt_binary(a, b, "|", c),
+			Op::Not(a, b) => format!("{} = ~{}", fmt_dest(a), b),
+			Op::Rmem(a, b) => format!("{} = mem[{}]", fmt_dest(a), b),
+			Op::Wmem(a, b) => format!("mem[{}] = {}", a, b),
+			Op::Call(Val::Num(m)) => format!("{}()", self.fn_name(m.to_usize())),
+			Op::Call(Val::Reg(r)) => format!("(*{})()", r),
+			Op::Out(Val::Num(n)) => format!("out({:?})", u16::from(n) as u8 as char),
+			Op::Out(v) => format!("out({})", v),
+			Op::In(a) => format!("{} = in()", fmt_dest(a)),
+			Op::Ret => "return".to_string(),
+			Op::Halt => "halt".to_string(),
+			Op::Jmp(_) | Op::Jt(_, _) | Op::Jf(_, _) => return,
+		};
+		self.line(s);
+	}
+
+	/// Emit blocks starting at `b` until `stop` is reached or control leaves
+	fn sequence(&mut self, mut b: usize, stop: Option<usize>, loops: &mut Vec<Loop>) {
+		loop {
+			if Some(b) == stop {
+				return;
+			}
+			if let Some(l) = loops.last() {
+				if l.header == b {
+					self.line("continue".into());
+					return;
+				}
+				if l.exit == Some(b) {
+					self.line("break".into());
+					return;
+				}
+			}
+			if self.emitted.contains_key(&b) {
+				self.labels.insert(b);
+				self.line(format!("goto L_{}", b));
+				return;
+			}
+			self.emitted.insert(b, self.lines.len());
+
+			if let Some(exit) = self.headers.get(&b).cloned() {
+				self.line("loop {".into());
+				self.indent += 1;
+				// Inside the body control only leaves through `break`, `return` or `goto`
+				loops.push(Loop { header: b, exit });
+				let next = self.block(b, None, loops);
+				if let Some(n) = next {
+					self.sequence(n, None, loops);
+				}
+				loops.pop();
+				self.indent -= 1;
+				self.line("}".into());
+				match exit {
+					Some(e) => b = e,
+					None => return,
+				}
+				continue;
+			}
+
+			match self.block(b, stop, loops) {
+				Some(n) => b = n,
+				None => return,
+			}
+		}
+	}
+
+	/// Emit one block, returning the block control continues with
+	fn block(&mut self, b: usize, stop: Option<usize>, loops: &mut Vec<Loop>) -> Option<usize> {
+		let cfg = self.cfg;
+		let block = &cfg.blocks[&b];
+		let mut depth = self.depth.get(&b).cloned().unwrap_or(None);
+		for (_, op) in block.ops.iter() {
+			self.statement(op, &mut depth);
+		}
+		let (_, last) = *block.ops.last()?;
+		let succs = block.succs.clone();
+		match last {
+			Op::Jt(_, _) | Op::Jf(_, _) if succs.len() == 2 => {
+				let taken = succs.iter().find(|(_, k)| *k == EdgeKind::Taken).map(|(a, _)| *a)?;
+				let fall = succs.iter().find(|(_, k)| *k == EdgeKind::Fallthrough).map(|(a, _)| *a)?;
+				let merge = self.ipdom[&b].filter(|m| Some(*m) != stop);
+				let (cond, first, second) = match Some(taken) == merge.or(stop) {
+					true => (fmt_cond(&last, true), fall, taken),
+					false => (fmt_cond(&last, false), taken, fall),
+				};
+				self.line(format!("if {} {{", cond));
+				self.indent += 1;
+				self.sequence(first, merge.or(stop), loops);
+				self.indent -= 1;
+				if Some(second) != merge && Some(second) != stop {
+					self.line("} else {".into());
+					self.indent += 1;
+					self.sequence(second, merge.or(stop), loops);
+					self.indent -= 1;
+				}
+				self.line("}".into());
+				merge
+			},
+			_ => succs.first().map(|(a, _)| *a),
+		}
+	}
+
+	pub fn decompile(mut self) -> String {
+		let entry = self.cfg.entry;
+		self.sequence(entry, None, &mut vec![]);
+		let mut rv = format!("{}() {{\n", self.fn_name(entry));
+		let mut lines = self.lines;
+		let mut at: Vec<(usize, usize)> = self.labels.iter().map(|l| (self.emitted[l], *l)).collect();
+		at.sort();
+		for (i, label) in at.into_iter().rev() {
+			lines.insert(i, format!("L_{}:", label));
+		}
+		for l in lines {
+			rv += &l;
+			rv.push('\n');
+		}
+		rv += "}\n";
+		rv
+	}
+}
+
+pub fn decompile(memory: &[u16], entry: usize, ann: &Annotations) -> anyhow::Result<String> {
+	let cfg = Cfg::build(memory, entry);
+	Ok(Decompiler::new(&cfg, ann)?.decompile())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_if_and_locals() {
+		// 0: jt r0 5; 3: ret; 4: noop; 5: push r0; 7: pop r1; 9: ret
+		let mem = [7, 32768, 5, 18, 21, 2, 32768, 3, 32769, 18];
+		let code = decompile(&mem, 0, &Annotations::default()).unwrap();
+		let expected = "fn_0() {
+    if r0 != 0 {
+        local0 = r0
+        r1 = local0
+        return
+    } else {
+        return
+    }
+}
+";
+		assert_eq!(code, expected);
+	}
+
+	#[test]
+	fn test_loop() {
+		// 0: add r0 r0 32767; 4: jt r0 0; 7: ret
+		let mem = [9, 32768, 32768, 32767, 7, 32768, 0, 18];
+		let code = decompile(&mem, 0, &Annotations::default()).unwrap();
+		let expected = "fn_0() {
+    loop {
+        r0 = r0 - 1
+        if r0 != 0 {
+            continue
+        }
+        break
+    }
+    return
+}
+";
+		assert_eq!(code, expected);
+	}
+
+	#[test]
+	fn test_no_entry() {
+		// Past the end and an undecodable word
+		assert!(decompile(&[18], 5, &Annotations::default()).is_err());
+		assert!(decompile(&[30000], 0, &Annotations::default()).is_err());
+	}
+}
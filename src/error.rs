@@ -0,0 +1,99 @@
+use std::fmt;
+
+/// Why the VM couldn't decode or execute an instruction. Every variant carries the pc
+/// and the raw words of the instruction (zero padded to 4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    InvalidOpcode { pc: usize, words: [u16; 4] },
+    /// Operand `index` (1-based, as in `words`) is not a literal or register,
+    /// or a register where only a literal is allowed
+    InvalidOperand { pc: usize, words: [u16; 4], index: usize, value: u16 },
+    EmptyStackPop { pc: usize, words: [u16; 4] },
+    ModByZero { pc: usize, words: [u16; 4] },
+    /// An address computed at runtime is past the 15-bit address space
+    AddressOutOfRange { pc: usize, words: [u16; 4], addr: u16 },
+    /// The other end of the screen was dropped while reading
+    InputClosed { pc: usize, words: [u16; 4] },
+    /// The other end of the screen was dropped while writing
+    OutputClosed { pc: usize, words: [u16; 4] },
+    /// The program ended, for callers that treat that as an error
+    Halt { pc: usize, words: [u16; 4] },
+}
+
+impl VmError {
+    pub fn pc(&self) -> usize {
+        match *self {
+            Self::InvalidOpcode { pc, .. }
+            | Self::InvalidOperand { pc, .. }
+            | Self::EmptyStackPop { pc, .. }
+            | Self::ModByZero { pc, .. }
+            | Self::AddressOutOfRange { pc, .. }
+            | Self::InputClosed { pc, .. }
+            | Self::OutputClosed { pc, .. }
+            | Self::Halt { pc, .. } => pc,
+        }
+    }
+
+    pub fn words(&self) -> [u16; 4] {
+        match *self {
+            Self::InvalidOpcode { words, .. }
+            | Self::InvalidOperand { words, .. }
+            | Self::EmptyStackPop { words, .. }
+            | Self::ModByZero { words, .. }
+            | Self::AddressOutOfRange { words, .. }
+            | Self::InputClosed { words, .. }
+            | Self::OutputClosed { words, .. }
+            | Self::Halt { words, .. } => words,
+        }
+    }
+
+    /// Same error at another pc, for decoding done without knowing the address
+    pub fn with_pc(mut self, at: usize) -> Self {
+        match &mut self {
+            Self::InvalidOpcode { pc, .. }
+            | Self::InvalidOperand { pc, .. }
+            | Self::EmptyStackPop { pc, .. }
+            | Self::ModByZero { pc, .. }
+            | Self::AddressOutOfRange { pc, .. }
+            | Self::InputClosed { pc, .. }
+            | Self::OutputClosed { pc, .. }
+            | Self::Halt { pc, .. } => *pc = at,
+        }
+        self
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode { words, .. } => write!(f, "Unknown op code {}", words[0])?,
+            Self::InvalidOperand { index, value, .. } => write!(f, "Invalid operand {}: {}", index, value)?,
+            Self::EmptyStackPop { .. } => write!(f, "Pop from empty stack")?,
+            Self::ModByZero { .. } => write!(f, "Mod by zero")?,
+            Self::AddressOutOfRange { addr, .. } => write!(f, "Address out of range: {}", addr)?,
+            Self::InputClosed { .. } => write!(f, "Input disconnected")?,
+            Self::OutputClosed { .. } => write!(f, "Output disconnected")?,
+            Self::Halt { .. } => write!(f, "Halted")?,
+        }
+        write!(f, " at {} {:?}", self.pc(), self.words())
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::op_parser::Op;
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Op::parse(&[25, 0, 0, 0]).unwrap_err().with_pc(7), VmError::InvalidOpcode { pc: 7, words: [25, 0, 0, 0] });
+        // Registers are only 32768..=32775, larger values are not "too small"
+        let err = Op::parse(&[1, 32776, 1]).unwrap_err();
+        assert_eq!(err, VmError::InvalidOperand { pc: 0, words: [1, 32776, 1, 0], index: 1, value: 32776 });
+        assert_eq!(err.to_string(), "Invalid operand 1: 32776 at 0 [1, 32776, 1, 0]");
+        // `set` needs a register to write to
+        assert!(matches!(Op::parse(&[1, 5, 1]), Err(VmError::InvalidOperand { index: 1, .. })));
+    }
+}
@@ -0,0 +1,537 @@
+//! GDB Remote Serial Protocol stub for `ExecutionEnv`.
+//!
+//! GDB addresses bytes while the VM addresses 16-bit words, so word `n` is exposed as
+//! bytes `2n` (low) and `2n + 1` (high), and the pc as the byte address of its word.
+//! The target description declares `r0`..`r7` and `pc` as 16-bit registers and no
+//! architecture, front ends that insist on one need `set architecture` to a 16-bit target.
+//! Watchpoints (`Z2`..`Z4`) cover every word their byte range touches, reverse stepping
+//! (`bs`, `bc`) goes back through the undo log.
+//! Game output is printed to stdout and stdin lines are sent to the game.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::error::VmError;
+use crate::op_parser::*;
+use crate::undo::UndoConfig;
+use crate::vm::{ExecutionEnv, Screen};
+use crate::watch::{Location, WatchId, WatchKind, WatchTarget};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.vm">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Register number of the pc, after the 8 general registers
+const PC_REG: usize = 8;
+
+/// Instructions run between checks for an interrupt from the client
+const POLL_INTERVAL: u64 = 100_000;
+
+/// What the client sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Data(String),
+    /// Ctrl-C, sent outside of a packet
+    Interrupt,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |a, b| a.wrapping_add(b))
+}
+
+/// Next packet from `input`, acknowledging it on `out`. `None` once the client is gone
+pub fn read_packet(input: &mut impl Read, out: &mut impl Write) -> io::Result<Option<Packet>> {
+    let mut byte = [0u8; 1];
+    loop {
+        if input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            0x03 => return Ok(Some(Packet::Interrupt)),
+            b'$' => {},
+            // Acks and noise between packets
+            _ => continue,
+        }
+        let mut data = vec![];
+        loop {
+            if input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        input.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected == Some(checksum(&data)) {
+            out.write_all(b"+")?;
+            return Ok(Some(Packet::Data(data)));
+        }
+        out.write_all(b"-")?;
+        out.flush()?;
+    }
+}
+
+pub fn write_packet(out: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(out, "${}#{:02x}", data, checksum(data))?;
+    out.flush()
+}
+
+fn hex_u16(v: u16) -> String {
+    format!("{:02x}{:02x}", v & 0xff, v >> 8)
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Little-endian u16 from 4 hex digits
+fn parse_hex_u16(s: &str) -> Option<u16> {
+    let bytes = parse_hex_bytes(s)?;
+    match bytes[..] {
+        [lo, hi] => Some(lo as u16 | (hi as u16) << 8),
+        _ => None,
+    }
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes().chunks(2)
+        .map(|c| match c {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `addr,len` as used by memory and breakpoint packets
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+/// Why `resume` returned
+enum Stop {
+    Trap,
+    Interrupted,
+    Exited,
+}
+
+pub struct GdbServer {
+    pub env: ExecutionEnv,
+    user: Screen,
+    breakpoints: BTreeSet<usize>,
+    /// Watchpoints by packet kind (`2` write, `3` read, `4` access) and byte range
+    watchpoints: BTreeMap<(char, usize, usize), Vec<WatchId>>,
+}
+
+impl GdbServer {
+    pub fn new(mut env: ExecutionEnv, user: Screen) -> Self {
+        env.record_undo(Some(UndoConfig::default()));
+        Self { env, user, breakpoints: BTreeSet::new(), watchpoints: BTreeMap::new() }
+    }
+
+    /// Wait for one client on `127.0.0.1:port` and serve it until it detaches
+    pub fn serve(&mut self, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!(">> Waiting for gdb on {}, e.g. `target remote :{}`", listener.local_addr()?, port);
+        let (stream, addr) = listener.accept()?;
+        println!(">> gdb connected from {}", addr);
+
+        let send = self.user.text_send.clone();
+        std::thread::spawn(move || {
+            for line in io::stdin().lines() {
+                let sent = line.map(|l| send.send(l + "\n").is_ok());
+                if !matches!(sent, Ok(true)) {
+                    break;
+                }
+            }
+        });
+        self.session(stream)
+    }
+
+    fn session(&mut self, stream: TcpStream) -> anyhow::Result<()> {
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut out = stream.try_clone()?;
+        while let Some(packet) = read_packet(&mut input, &mut out)? {
+            let data = match packet {
+                Packet::Data(data) => data,
+                // Only meaningful while running, see `interrupted`
+                Packet::Interrupt => continue,
+            };
+            let reply = self.handle(&data, &mut || interrupted(&stream));
+            self.print_output()?;
+            match reply {
+                Some(reply) => write_packet(&mut out, &reply)?,
+                None => {
+                    write_packet(&mut out, "OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print_output(&mut self) -> anyhow::Result<()> {
+        let output = self.user.get_all()?;
+        if !output.is_empty() {
+            print!("{}", output);
+            io::stdout().flush()?;
+        }
+        Ok(())
+    }
+
+    fn register(&self, n: usize) -> Option<u16> {
+        match n {
+            0..=7 => Some(self.env.registers[n]),
+            PC_REG => Some(self.env.curr_point.to_usize() as u16 * 2),
+            _ => None,
+        }
+    }
+
+    /// Registers hold 15-bit values and the pc is a byte address, so it must be even
+    fn set_register(&mut self, n: usize, v: u16) -> Option<()> {
+        match n {
+            0..=7 if v <= 32767 => self.env.set_register(n, v),
+            PC_REG if v.is_multiple_of(2) => self.env.set_pc((v / 2).try_into().ok()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Option<String> {
+        (addr..addr + len)
+            .map(|b| self.env.memory.get(b / 2).map(|w| format!("{:02x}", w.to_le_bytes()[b % 2])))
+            .collect()
+    }
+
+    fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Option<()> {
+        if (addr + bytes.len()).div_ceil(2) > self.env.memory.len() {
+            return None;
+        }
+        for (i, b) in bytes.iter().enumerate() {
+            let word = (addr + i) / 2;
+            let mut le = self.env.memory[word].to_le_bytes();
+            le[(addr + i) % 2] = *b;
+            self.env.poke((word as u16).try_into().ok()?, u16::from_le_bytes(le));
+        }
+        Some(())
+    }
+
+    /// Run for `limit` instructions at most, or until a breakpoint, the end of the
+    /// program or an interrupt. Waiting for input polls until some arrives.
+    fn resume(&mut self, limit: Option<u64>, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+        let mut count = 0;
+        loop {
+            let step = match self.env.step_or_halt() {
+                Ok(step) => step,
+                Err(VmError::Halt { .. }) => return Stop::Exited,
+                Err(e) => {
+                    println!(">> {}", e);
+                    return Stop::Exited;
+                }
+            };
+            if step.waiting_for_input {
+                let _ = self.print_output();
+                std::thread::sleep(Duration::from_millis(50));
+            } else {
+                count += 1;
+                if step.stopped || Some(count) == limit || self.breakpoints.contains(&step.pc_after) {
+                    return Stop::Trap;
+                }
+            }
+            if (step.waiting_for_input || count % POLL_INTERVAL == 0) && interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    /// Stop reply after a trap, naming the watchpoint if one was hit
+    fn trap_reply(&mut self) -> String {
+        let hits = self.env.watches.take_hits();
+        let watched = hits.iter().find_map(|hit| {
+            let ((kind, _, _), _) = self.watchpoints.iter().find(|(_, ids)| ids.contains(&hit.id))?;
+            match hit.location {
+                Location::Mem(a) => Some((*kind, a * 2)),
+                Location::Reg(_) => None,
+            }
+        });
+        match watched {
+            Some(('2', addr)) => format!("T05watch:{:x};", addr),
+            Some(('3', addr)) => format!("T05rwatch:{:x};", addr),
+            Some((_, addr)) => format!("T05awatch:{:x};", addr),
+            None => "S05".to_string(),
+        }
+    }
+
+    /// Reply to one packet, `None` when the session should end
+    pub fn handle(&mut self, data: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let ok = |r: Option<()>| r.map(|_| "OK".to_string()).unwrap_or_else(|| "E01".to_string());
+        let (cmd, rest) = data.split_at(data.len().min(1));
+        Some(match cmd {
+            "?" => "S05".to_string(),
+            "g" => (0..=PC_REG).map(|n| hex_u16(self.register(n).unwrap())).collect(),
+            "G" => ok((0..=PC_REG).try_for_each(|n| {
+                let v = parse_hex_u16(rest.get(n * 4..n * 4 + 4)?)?;
+                self.set_register(n, v)
+            })),
+            "p" => match parse_hex(rest).and_then(|n| self.register(n)) {
+                Some(v) => hex_u16(v),
+                None => "E01".to_string(),
+            },
+            "P" => ok(rest.split_once('=').and_then(|(n, v)| self.set_register(parse_hex(n)?, parse_hex_u16(v)?))),
+            "m" => parse_range(rest)
+                .and_then(|(addr, len)| self.read_memory(addr, len))
+                .unwrap_or_else(|| "E01".to_string()),
+            "M" => ok(rest.split_once(':').and_then(|(range, bytes)| {
+                let (addr, len) = parse_range(range)?;
+                let bytes = parse_hex_bytes(bytes)?;
+                if bytes.len() != len {
+                    return None;
+                }
+                self.write_memory(addr, &bytes)
+            })),
+            "Z" | "z" => {
+                let (kind, range) = rest.split_once(',').unwrap_or((rest, ""));
+                match (kind, parse_range(range)) {
+                    ("0" | "1", Some((addr, _))) => {
+                        if cmd == "Z" {
+                            self.breakpoints.insert(addr / 2);
+                        } else {
+                            self.breakpoints.remove(&(addr / 2));
+                        }
+                        "OK".to_string()
+                    },
+                    ("2" | "3" | "4", Some((addr, len))) if len > 0 => {
+                        let kind = kind.chars().next().unwrap();
+                        if cmd == "z" {
+                            for id in self.watchpoints.remove(&(kind, addr, len)).into_iter().flatten() {
+                                self.env.watches.remove(id);
+                            }
+                            return Some("OK".to_string());
+                        }
+                        let target = WatchTarget::Mem(addr / 2, (addr + len - 1) / 2);
+                        let kinds = match kind {
+                            '2' => &[WatchKind::Write][..],
+                            '3' => &[WatchKind::Read],
+                            _ => &[WatchKind::Read, WatchKind::Write],
+                        };
+                        let ids = kinds.iter().map(|k| self.env.watches.add(target, *k)).collect();
+                        self.watchpoints.insert((kind, addr, len), ids);
+                        "OK".to_string()
+                    },
+                    _ => String::new(),
+                }
+            },
+            "s" | "c" => {
+                if let Some(addr) = parse_hex(rest) {
+                    if u16::try_from(addr).ok().and_then(|a| self.set_register(PC_REG, a)).is_none() {
+                        return Some("E01".to_string());
+                    }
+                }
+                let limit = if cmd == "s" { Some(1) } else { None };
+                match self.resume(limit, interrupted) {
+                    Stop::Trap => self.trap_reply(),
+                    Stop::Interrupted => "S02".to_string(),
+                    Stop::Exited => "W00".to_string(),
+                }
+            },
+            // Reverse execution over the undo log, running out of it is reported as such
+            "b" => {
+                let went_back = match rest {
+                    "s" => self.env.step_back(1) == 1,
+                    "c" => {
+                        let breakpoints = &self.breakpoints;
+                        self.env.run_back_until(|env| breakpoints.contains(&env.curr_point.to_usize()))
+                    },
+                    _ => return Some(String::new()),
+                };
+                match went_back {
+                    true => "S05".to_string(),
+                    false => "T05replaylog:begin;".to_string(),
+                }
+            },
+            "H" => "OK".to_string(),
+            "k" | "D" => return None,
+            "q" => match rest {
+                _ if rest.starts_with("Supported") => "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string(),
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => match rest.strip_prefix("Xfer:features:read:target.xml:").and_then(parse_range) {
+                    Some((offset, len)) => {
+                        let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                        match chunk.len() > len {
+                            true => format!("m{}", &chunk[..len]),
+                            false => format!("l{}", chunk),
+                        }
+                    },
+                    None => String::new(),
+                },
+            },
+            // Anything else is unsupported, which an empty reply says
+            _ => String::new(),
+        })
+    }
+}
+
+/// Whether the client sent a Ctrl-C, without blocking
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let rv = match stream.peek(&mut byte) {
+        Ok(1) if byte[0] == 0x03 => (&*stream).read(&mut byte).is_ok(),
+        _ => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+
+    fn server() -> GdbServer {
+        let words = assemble("
+            set r0 2
+            loop: add r0 r0 32767
+            jt r0 loop
+            out 'x'
+            halt
+        ").unwrap();
+        let (screen, user) = Screen::create();
+        GdbServer::new(ExecutionEnv::new(&to_bytes(&words), screen, None), user)
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut input = &b"+$qAttached#8f$m0,2#fb\x03$g#00"[..];
+        let mut acks = vec![];
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), Some(Packet::Data("qAttached".into())));
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), Some(Packet::Data("m0,2".into())));
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), Some(Packet::Interrupt));
+        // The bad checksum is nacked and the stream ends
+        assert_eq!(read_packet(&mut input, &mut acks).unwrap(), None);
+        assert_eq!(acks, b"++-");
+
+        let mut out = vec![];
+        write_packet(&mut out, "OK").unwrap();
+        assert_eq!(out, b"$OK#9a");
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut gdb = server();
+        let mut handle = |data: &str| gdb.handle(data, &mut || false).unwrap();
+        // set r0 2 is 0001 8000 0002
+        assert_eq!(handle("m0,6"), "010000800200");
+        assert_eq!(handle("s"), "S05");
+        assert_eq!(handle("g"), format!("0200{}0600", "0000".repeat(7)));
+        assert_eq!(handle("P7=3412"), "OK");
+        assert_eq!(handle("p7"), "3412");
+        assert_eq!(handle("p9"), "E01");
+        assert_eq!(handle("P0=0080"), "E01");
+        assert_eq!(handle("P8=0300"), "E01");
+        assert_eq!(handle("s3"), "E01");
+        assert_eq!(handle("s20000"), "E01");
+        assert_eq!(handle("p8"), "0600");
+        // Break on the `out`, at word 10
+        assert_eq!(handle("Z0,14,2"), "OK");
+        assert_eq!(handle("c"), "S05");
+        assert_eq!(handle("p8"), "1400");
+        // Turn the `out` into a `halt`
+        assert_eq!(handle("M14,2:0000"), "OK");
+        assert_eq!(handle("z0,14,2"), "OK");
+        assert_eq!(handle("c"), "W00");
+        assert!(handle("qXfer:features:read:target.xml:0,20").starts_with("m<?xml"));
+        assert!(handle("qXfer:features:read:target.xml:20,1000").starts_with('l'));
+        assert_eq!(handle("vMustReplyEmpty"), "");
+        assert_eq!(handle("m0,100000"), "E01");
+        assert!(gdb.handle("D", &mut || false).is_none());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let words = assemble("
+            wmem 100 1
+            rmem r0 101
+            wmem 101 2
+            halt
+        ").unwrap();
+        let (screen, user) = Screen::create();
+        let mut gdb = GdbServer::new(ExecutionEnv::new(&to_bytes(&words), screen, None), user);
+        let mut handle = |data: &str| gdb.handle(data, &mut || false).unwrap();
+        // Words 100 and 101 are bytes c8..cb
+        assert_eq!(handle("Z2,c8,4"), "OK");
+        assert_eq!(handle("Z3,ca,2"), "OK");
+        assert_eq!(handle("c"), "T05watch:c8;");
+        assert_eq!(handle("c"), "T05rwatch:ca;");
+        assert_eq!(handle("z2,c8,4"), "OK");
+        assert_eq!(handle("z3,ca,2"), "OK");
+        assert_eq!(handle("Z4,ca,2"), "OK");
+        assert_eq!(handle("c"), "T05awatch:ca;");
+        assert_eq!(handle("c"), "W00");
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut gdb = server();
+        let mut handle = |data: &str| gdb.handle(data, &mut || false).unwrap();
+        assert_eq!(handle("Z0,14,2"), "OK");
+        assert_eq!(handle("c"), "S05");
+        assert_eq!(handle("bs"), "S05");
+        // Back on the `jt` at word 7, with r0 counted down to 0
+        assert_eq!(handle("p8"), "0e00");
+        assert_eq!(handle("p0"), "0000");
+        // The write is undone on its own
+        assert_eq!(handle("P1=0100"), "OK");
+        assert_eq!(handle("bs"), "S05");
+        assert_eq!(handle("p1"), "0000");
+        assert_eq!(handle("p8"), "0e00");
+        // So is moving the pc, `s addr` included
+        assert_eq!(handle("s6"), "S05");
+        assert_eq!(handle("bs"), "S05");
+        assert_eq!(handle("p8"), "0600");
+        assert_eq!(handle("bs"), "S05");
+        assert_eq!(handle("p8"), "0e00");
+        // Back to each run of the `add` at word 3, then to the start
+        assert_eq!(handle("Z0,6,2"), "OK");
+        assert_eq!(handle("bc"), "S05");
+        assert_eq!(handle("p0"), "0100");
+        assert_eq!(handle("bc"), "S05");
+        assert_eq!(handle("p0"), "0200");
+        assert_eq!(handle("bc"), "T05replaylog:begin;");
+        assert_eq!(handle("p8"), "0000");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let words = assemble("loop: jmp loop").unwrap();
+        let (screen, user) = Screen::create();
+        let mut gdb = GdbServer::new(ExecutionEnv::new(&to_bytes(&words), screen, None), user);
+        let mut polls = 0;
+        assert_eq!(gdb.handle("c", &mut || { polls += 1; polls == 3 }).unwrap(), "S02");
+        assert_eq!(gdb.env.operation_count, 3 * POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use crate::op_parser::*;
+use crate::vm::{ExecutionEnv, Screen};
+use crate::vm_io::Io;
+
+/// What the interpreter does with the instruction a hook fired on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+	/// Execute the instruction normally
+	Continue,
+	/// Don't execute it, move on to the next instruction
+	Skip,
+	/// Don't execute it and stop the running loop, pc stays on the instruction
+	Stop,
+	/// Execute the given op instead
+	Replace(Op),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+	/// Before executing the instruction at this address
+	Addr(usize),
+	/// Before executing any instruction with this opcode
+	Opcode(u16),
+	/// Before the instruction that brings `operation_count` to this value
+	OpCount(u64),
+	/// Before an `in`
+	Input,
+	/// Before an `out`
+	Output,
+	/// Before a `call` whose (resolved) target is this address
+	Call(usize),
+}
+
+impl fmt::Display for Trigger {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::Addr(a) => write!(f, "at {}", a),
+			Self::Opcode(o) => write!(f, "op {}", OPCODES.get(o as usize).map(|(n, _)| *n).unwrap_or("?")),
+			Self::OpCount(n) => write!(f, "ops {}", n),
+			Self::Input => write!(f, "in"),
+			Self::Output => write!(f, "out"),
+			Self::Call(a) => write!(f, "call {}", a),
+		}
+	}
+}
+
+pub type HookFn<S = Screen> = Box<dyn FnMut(&mut ExecutionEnv<S>, &Op) -> HookAction + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookId(usize);
+
+/// Callbacks run by `ExecutionEnv` before every matching instruction. The first hook
+/// returning anything other than `Continue` decides what happens to the instruction.
+pub struct Hooks<S: Io = Screen> {
+	hooks: Vec<(HookId, Trigger, HookFn<S>)>,
+	next_id: usize,
+	/// Removals of hooks that weren't found, applied on `merge`
+	removed: Vec<HookId>,
+}
+
+impl<S: Io> Default for Hooks<S> {
+	fn default() -> Self {
+		Self { hooks: vec![], next_id: 0, removed: vec![] }
+	}
+}
+
+impl<S: Io> Hooks<S> {
+	pub fn add(&mut self, trigger: Trigger, f: HookFn<S>) -> HookId {
+		let id = HookId(self.next_id);
+		self.next_id += 1;
+		self.hooks.push((id, trigger, f));
+		id
+	}
+
+	pub fn remove(&mut self, id: HookId) {
+		let len = self.hooks.len();
+		self.hooks.retain(|(i, _, _)| *i != id);
+		if self.hooks.len() == len {
+			self.removed.push(id);
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.hooks.is_empty()
+	}
+
+	/// Apply what hooks registered or removed while these were taken out of the env
+	pub(crate) fn merge(&mut self, added: Hooks<S>) {
+		self.next_id = self.next_id.max(added.next_id);
+		self.hooks.extend(added.hooks);
+		self.hooks.retain(|(i, _, _)| !added.removed.contains(i));
+	}
+
+	/// Run every hook matching `op` about to execute in `env`
+	pub(crate) fn fire(&mut self, env: &mut ExecutionEnv<S>, op: &Op) -> HookAction {
+		for (_, trigger, f) in self.hooks.iter_mut() {
+			let matches = match *trigger {
+				Trigger::Addr(a) => env.curr_point.to_usize() == a,
+				Trigger::Opcode(o) => op.opcode() == o,
+				Trigger::OpCount(n) => env.operation_count == n,
+				Trigger::Input => matches!(op, Op::In(_)),
+				Trigger::Output => matches!(op, Op::Out(_)),
+				Trigger::Call(a) => match op {
+					Op::Call(v) => env.value_of(*v) as usize == a,
+					_ => false,
+				},
+			};
+			if !matches {
+				continue;
+			}
+			match f(env, op) {
+				HookAction::Continue => {},
+				action => return action,
+			}
+		}
+		HookAction::Continue
+	}
+}
+
+/// Hook making the function it fires on return immediately with the given registers set
+pub fn stub<S: Io>(registers: Vec<(usize, MemBlock)>) -> HookFn<S> {
+	Box::new(move |env, _| {
+		for (r, v) in registers.iter() {
+			env.registers[*r] = *v;
+		}
+		HookAction::Replace(Op::Ret)
+	})
+}
+
+/// Hook setting register `reg` to `value`
+pub fn set_register<S: Io>(reg: usize, value: MemBlock) -> HookFn<S> {
+	Box::new(move |env, _| {
+		env.registers[reg] = value;
+		HookAction::Continue
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::assembler::{assemble, to_bytes};
+	use crate::vm::Screen;
+
+	fn env(source: &str) -> (ExecutionEnv, Screen) {
+		let words = assemble(source).unwrap();
+		let (screen, user) = Screen::create();
+		(ExecutionEnv::new(&to_bytes(&words), screen, None), user)
+	}
+
+	#[test]
+	fn test_stub_and_op_count() {
+		// The same setup as the teleporter hacks: a stubbed routine and r7 set late
+		let (mut env, mut user) = env("
+			call check
+			out r0
+			out r7
+			halt
+			check: set r0 'n'
+			ret
+		");
+		env.hooks.add(Trigger::Addr(7), stub(vec![(0, 'y' as u16)]));
+		env.hooks.add(Trigger::OpCount(3), set_register(7, '!' as u16));
+		env.run().unwrap();
+		assert_eq!(user.get_all().unwrap(), "y!");
+	}
+
+	#[test]
+	fn test_stop_and_skip() {
+		let (mut env, mut user) = env("
+			out 'a'
+			call f
+			out 'c'
+			halt
+			f: out 'b'
+			ret
+		");
+		env.record_undo(Some(Default::default()));
+		env.hooks.add(Trigger::Call(7), Box::new(|_, _| HookAction::Stop));
+		let skip = env.hooks.add(Trigger::Output, Box::new(|_, op| match op {
+			Op::Out(Val::Num(n)) if u16::from(*n) == 'b' as u16 => HookAction::Skip,
+			_ => HookAction::Continue,
+		}));
+		env.run().unwrap();
+		assert_eq!(user.get_all().unwrap(), "a");
+		assert_eq!(env.curr_point.to_usize(), 2);
+		assert_eq!(env.operation_count, 1);
+
+		// Resuming runs the instruction the hook stopped on
+		env.run().unwrap();
+		assert_eq!(user.get_all().unwrap(), "c");
+		// Neither the stopped nor the skipped instruction counts or can be stepped back
+		assert_eq!(env.operation_count, 5);
+		assert_eq!(env.undo.as_ref().unwrap().len(), 5);
+		env.hooks.remove(skip);
+		assert!(!env.hooks.is_empty());
+	}
+}
@@ -1,15 +1,35 @@
-use std::{sync::{Mutex, Arc}, process::exit, io::{Write, Read}, path::PathBuf};
+use std::{io::{Write, Read}, path::PathBuf};
 
 use anyhow::bail;
 use either::*;
 use vm::StaticExecuter;
+use annotations::Annotations;
+use patch::PatchSet;
 use clap::Parser;
 
-// mod async_vm;
-// mod vm_runner;
+mod vm_runner;
 mod vm;
 mod op_parser;
 mod reverse_engineer;
+mod cfg;
+mod callgraph;
+mod decompiler;
+mod xrefs;
+mod annotations;
+mod assembler;
+mod checkpoint;
+mod patch;
+mod hooks;
+mod error;
+mod vm_io;
+mod undo;
+mod watch;
+mod condition;
+mod trace;
+mod trace_diff;
+mod debugger;
+mod gdb_server;
+mod dap;
 
 
 #[derive(Parser, Debug)]
@@ -17,9 +37,364 @@ mod reverse_engineer;
 struct Args {
    #[arg(short, long)]
    checkpoint: Option<PathBuf>,
+   /// Json file with address names and comments
+   #[arg(short, long, global = true)]
+   annotations: Option<PathBuf>,
+   /// Json patch files applied to the image at load time, defaults to teleporter_patch.json
+   #[arg(short, long)]
+   patch: Vec<PathBuf>,
+   /// Record a binary execution trace of the game to this file (see `trace-dump`)
+   #[arg(short, long)]
+   trace: Option<PathBuf>,
+   /// Serve the game to a gdb remote protocol client on this local port instead of playing it
+   #[arg(long)]
+   gdb_port: Option<u16>,
+   #[command(subcommand)]
+   command: Option<SubCommand>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum SubCommand {
+   /// Write a disassembly listing of a binary image
+   Disassemble {
+      /// Image to disassemble
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Listing destination, stdout if not given
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+      /// Follow control flow from the entry points instead of sweeping linearly
+      #[arg(short, long)]
+      recursive: bool,
+      /// Entry points for the recursive mode
+      #[arg(short, long, default_value = "0")]
+      entry: Vec<usize>,
+      /// Input is an EnvSnapshot json (see the `snapshot` game command)
+      #[arg(short, long)]
+      snapshot: bool,
+   },
+   /// Export control-flow graphs of functions as Graphviz DOT
+   Cfg {
+      /// Image to analyse
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// DOT destination, stdout if not given
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+      /// Function entry points, defaults to every function reached from address 0
+      #[arg(short, long)]
+      entry: Vec<usize>,
+      /// Input is an EnvSnapshot json
+      #[arg(short, long)]
+      snapshot: bool,
+   },
+   /// Discover functions and export the call graph
+   Callgraph {
+      /// Image to analyse
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Destination, stdout if not given
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+      #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+      format: GraphFormat,
+      /// Input is an EnvSnapshot json
+      #[arg(short, long)]
+      snapshot: bool,
+   },
+   /// Assemble a source file into a binary image
+   Assemble {
+      input: PathBuf,
+      /// Image destination, defaults to the input with a `.bin` extension
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+   },
+   /// Print structured pseudo-code of the function at `addr`
+   Decompile {
+      addr: usize,
+      /// Image to analyse
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Destination, stdout if not given
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+      /// Input is an EnvSnapshot json
+      #[arg(short, long)]
+      snapshot: bool,
+   },
+   /// Measure interpreter speed with and without the decoded instruction cache
+   Bench {
+      /// Image to run
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Instructions to run per measurement
+      #[arg(short = 'n', long, default_value = "5000000")]
+      ops: u64,
+      /// Checkpoint json whose commands are fed to the game
+      #[arg(short, long)]
+      replay: Option<PathBuf>,
+   },
+   /// Run an image without interaction, feeding it a checkpoint's commands, and record its trace
+   Trace {
+      /// Trace destination
+      output: PathBuf,
+      /// Image to run
+      #[arg(short, long, default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Checkpoint json whose commands are fed to the game
+      #[arg(short, long)]
+      replay: Option<PathBuf>,
+      /// Value the 8th register gets once the self-test is done
+      #[arg(long, default_value = "25734")]
+      register_8: u16,
+      /// Stop after this many instructions
+      #[arg(short = 'n', long)]
+      ops: Option<u64>,
+   },
+   /// Report where two traces first differ, e.g. runs with different 8th register values,
+   /// and where they line up again if one took a detour
+   TraceDiff {
+      left: PathBuf,
+      right: PathBuf,
+      /// Records of context shown around the divergence
+      #[arg(short = 'C', long, default_value = "5")]
+      context: usize,
+   },
+   /// Run the game under an interactive debugger, see `help` at its prompt
+   Debug {
+      /// Image to run
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Checkpoint json whose commands are queued as game input
+      #[arg(short, long)]
+      replay: Option<PathBuf>,
+      /// Value the 8th register gets once the self-test is done
+      #[arg(long, default_value = "25734")]
+      register_8: u16,
+   },
+   /// Serve the Debug Adapter Protocol over stdio, for debugging from an editor
+   Dap,
+   /// Play the game on the tokio runtime, with stdin lines as input and no custom commands
+   Play {
+      /// Image to run
+      #[arg(default_value = "challenge.bin")]
+      input: PathBuf,
+      /// Checkpoint json whose commands are queued as game input
+      #[arg(short, long)]
+      replay: Option<PathBuf>,
+      /// Value the 8th register gets once the self-test is done
+      #[arg(long, default_value = "25734")]
+      register_8: u16,
+   },
+   /// Print a binary execution trace as text
+   TraceDump {
+      input: PathBuf,
+      /// Destination, stdout if not given
+      #[arg(short, long)]
+      output: Option<PathBuf>,
+      /// Only instructions at or after this address
+      #[arg(long)]
+      from: Option<usize>,
+      /// Only instructions at or before this address
+      #[arg(long)]
+      to: Option<usize>,
+      /// Only these instructions, by mnemonic
+      #[arg(long = "op")]
+      ops: Vec<String>,
+   },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum GraphFormat {
+   Dot,
+   Json,
+}
+
+impl SubCommand {
+    fn run(&self, ann: &Annotations, patches: &PatchSet) -> anyhow::Result<()> {
+        match self {
+            Self::Disassemble { input, output, recursive, entry, snapshot } => {
+                let content = std::fs::read(input)?;
+                let listing = if *snapshot {
+                    let snapshot = vm::EnvSnapshot::from_json(std::str::from_utf8(&content)?)?;
+                    reverse_engineer::disassemble_snapshot(&snapshot, ann)
+                } else if *recursive {
+                    reverse_engineer::disassemble_recursive(&op_parser::Op::convert_bytes(&content), entry, ann)
+                } else {
+                    reverse_engineer::parse(&content, ann)?
+                };
+                write_output(output.as_ref(), &listing)
+            },
+            Self::Cfg { input, output, entry, snapshot } => {
+                let (memory, roots) = load_memory(input, *snapshot)?;
+                let entries = match entry.is_empty() {
+                    true => cfg::function_entries(&memory, &roots),
+                    false => entry.iter().cloned().collect(),
+                };
+                let cfgs: Vec<cfg::Cfg> = entries.into_iter()
+                    .map(|e| cfg::Cfg::build(&memory, e))
+                    .collect();
+                write_output(output.as_ref(), &cfg::to_dot(&cfgs, ann))
+            },
+            Self::Callgraph { input, output, format, snapshot } => {
+                let (memory, roots) = load_memory(input, *snapshot)?;
+                let graph = callgraph::CallGraph::discover(&memory, &roots);
+                let content = match format {
+                    GraphFormat::Dot => graph.to_dot(ann),
+                    GraphFormat::Json => graph.to_json(),
+                };
+                write_output(output.as_ref(), &content)
+            },
+            Self::Assemble { input, output } => {
+                let source = std::fs::read_to_string(input)?;
+                let words = assembler::assemble(&source)?;
+                let output = output.clone().unwrap_or_else(|| input.with_extension("bin"));
+                std::fs::write(&output, assembler::to_bytes(&words))?;
+                println!("Wrote {} words to {:?}", words.len(), output);
+                Ok(())
+            },
+            Self::Decompile { addr, input, output, snapshot } => {
+                let (memory, _) = load_memory(input, *snapshot)?;
+                if *addr >= memory.len() {
+                    bail!("Address out of range: {}", addr);
+                }
+                write_output(output.as_ref(), &decompiler::decompile(&memory, *addr, ann)?)
+            },
+            Self::Bench { input, ops, replay } => {
+                let image = std::fs::read(input)?;
+                let lines = match replay {
+                    Some(p) => checkpoint::load(p)?,
+                    None => vec![],
+                };
+                for cached in [false, true] {
+                    let mut env = vm::ExecutionEnv::with_patches(&image, vm_io::BufferIo::new(""), Some(25734), patches)?;
+                    env.set_decode_cache(cached);
+                    // Runtime writes are made before timing starts
+                    checkpoint::replay(&mut env, &lines, queue_input)?;
+                    let before = env.operation_count;
+                    let start = std::time::Instant::now();
+                    env.run_for(*ops, |_, _| {})?;
+                    let elapsed = start.elapsed();
+                    let count = env.operation_count - before;
+                    println!("{:>8}: {} ops in {:.2?}, {:.0} ops/s",
+                        if cached { "cached" } else { "uncached" },
+                        count, elapsed,
+                        count as f64 / elapsed.as_secs_f64());
+                }
+                Ok(())
+            },
+            Self::Trace { output, input, replay, register_8, ops } => {
+                let image = std::fs::read(input)?;
+                let lines = match replay {
+                    Some(p) => checkpoint::load(p)?,
+                    None => vec![],
+                };
+                let mut env = vm::ExecutionEnv::with_patches(&image, vm_io::BufferIo::new(""), Some(*register_8), patches)?;
+                env.start_trace(Box::new(std::io::BufWriter::new(std::fs::File::create(output)?)))?;
+                checkpoint::replay(&mut env, &lines, queue_input)?;
+                // Ends at a halt or once the game waits for more commands than the replay has
+                if let Err(e) = env.run_for(ops.unwrap_or(u64::MAX), |_, _| {}) {
+                    println!("Stopped by {}", e);
+                }
+                env.stop_trace()?;
+                println!("Traced {} ops to {:?}", env.operation_count, output);
+                Ok(())
+            },
+            Self::TraceDiff { left, right, context } => {
+                let open = |p: &PathBuf| -> anyhow::Result<_> {
+                    Ok(trace::TraceReader::new(std::io::BufReader::new(std::fs::File::open(p)?))?)
+                };
+                match trace_diff::first_divergence(open(left)?, open(right)?, *context)? {
+                    Some(d) => print!("{}", d.format(ann)),
+                    None => println!("Traces are identical"),
+                }
+                Ok(())
+            },
+            Self::Debug { input, replay, register_8 } => {
+                let image = std::fs::read(input)?;
+                let mut dbg = debugger::Debugger::new(&image, Some(*register_8), patches, ann)?;
+                if let Some(p) = replay {
+                    dbg.replay(&checkpoint::load(p)?)?;
+                }
+                dbg.repl(std::io::stdin().lock(), &mut std::io::stdout())
+            },
+            Self::Dap => dap::DapServer::new(std::io::stdout(), ann, patches).serve(std::io::BufReader::new(std::io::stdin())),
+            Self::Play { input, replay, register_8 } => {
+                let image = std::fs::read(input)?;
+                let lines = match replay {
+                    Some(p) => checkpoint::load(p)?,
+                    None => vec![],
+                };
+                let (screen, mut user) = vm_io::AsyncScreen::create();
+                let mut env = Box::new(vm::ExecutionEnv::with_patches(&image, screen, Some(*register_8), patches)?);
+                checkpoint::replay(&mut env, &lines, |_, s| user.send(s.to_string()))?;
+                let mut runner = vm_runner::Runner::new(user);
+                tokio::runtime::Runtime::new()?.block_on(async {
+                    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+                    runner.run(env, stdin, &mut tokio::io::stdout()).await
+                })
+            },
+            Self::TraceDump { input, output, from, to, ops } => {
+                let mut filter = trace::TraceFilter::default();
+                if from.is_some() || to.is_some() {
+                    filter.range = Some((from.unwrap_or(0), to.unwrap_or(usize::MAX)));
+                }
+                for name in ops.iter() {
+                    match op_parser::OPCODES.iter().position(|(n, _)| n == name) {
+                        Some(code) => filter.opcodes.push(code as u16),
+                        None => bail!("Unknown instruction {:?}", name),
+                    }
+                }
+                let reader = trace::TraceReader::new(std::io::BufReader::new(std::fs::File::open(input)?))?;
+                match output {
+                    Some(p) => trace::dump(reader, &filter, ann, &mut std::io::BufWriter::new(std::fs::File::create(p)?)),
+                    None => trace::dump(reader, &filter, ann, &mut std::io::stdout().lock()),
+                }
+            }
+        }
+    }
+}
+
+/// Memory words of a binary image or of an EnvSnapshot json, along with the
+/// known code roots (address 0, and for snapshots the pc and return addresses)
+fn load_memory(input: &PathBuf, snapshot: bool) -> anyhow::Result<(Vec<u16>, Vec<usize>)> {
+    let content = std::fs::read(input)?;
+    if snapshot {
+        let snapshot = vm::EnvSnapshot::from_json(std::str::from_utf8(&content)?)?;
+        let roots = reverse_engineer::snapshot_roots(&snapshot);
+        Ok((snapshot.memory, roots))
+    } else {
+        Ok((op_parser::Op::convert_bytes(&content), vec![0]))
+    }
+}
+
+fn write_output(path: Option<&PathBuf>, content: &str) -> anyhow::Result<()> {
+    match path {
+        Some(p) => std::fs::write(p, content)?,
+        None => print!("{}", content),
+    }
+    Ok(())
 }
 
 impl Args {
+    fn get_annotations(&self) -> anyhow::Result<Annotations> {
+        match &self.annotations {
+            Some(path) => Annotations::load(path),
+            None => Ok(Annotations::default()),
+        }
+    }
+
+    fn get_patches(&self) -> anyhow::Result<PatchSet> {
+        if self.patch.is_empty() {
+            return PatchSet::from_json(include_str!("../teleporter_patch.json"));
+        }
+        let mut rv = PatchSet::default();
+        for p in self.patch.iter() {
+            rv.extend(PatchSet::load(p)?);
+        }
+        Ok(rv)
+    }
+
     fn get_replay(&self) -> anyhow::Result<Vec<String>> {
         let mut replay_codes = vec![];
 
@@ -33,12 +408,18 @@ impl Args {
     }
 }
 
+#[derive(Debug, PartialEq)]
 enum CustomCommand {
-    Save(PathBuf)
+    Save(PathBuf),
+    Snapshot(PathBuf),
+    Xrefs(usize),
+    /// `!peek` reads the live VM, `!reg` and `!poke` writes are kept in the history
+    Peek(usize, usize),
+    Write(checkpoint::RuntimeWrite),
 }
 
 impl CustomCommand {
-    fn parse(mut cmd: &str) -> anyhow::Result<Option<Self>> {
+    fn parse(mut cmd: &str, ann: &Annotations) -> anyhow::Result<Option<Self>> {
         if cmd.starts_with("save") {
             cmd = cmd.trim();
             match cmd.strip_prefix("save ") {
@@ -48,27 +429,63 @@ impl CustomCommand {
                 }
             }
         }
-        return Ok(None)
+        if cmd.starts_with("snapshot") {
+            cmd = cmd.trim();
+            match cmd.strip_prefix("snapshot ") {
+                None => bail!(">> Usage: snapshot <file_path>"),
+                Some(x) => {
+                    return Ok(Some(Self::Snapshot(x.into())));
+                }
+            }
+        }
+        if cmd.starts_with("xrefs") {
+            cmd = cmd.trim();
+            match cmd.strip_prefix("xrefs ") {
+                None => bail!(">> Usage: xrefs <address|label>"),
+                Some(x) => {
+                    return Ok(Some(Self::Xrefs(ann.parse_addr(x)?)));
+                }
+            }
+        }
+        let args: Vec<&str> = match cmd.trim().strip_prefix('!') {
+            Some(x) => x.split_whitespace().collect(),
+            None => return Ok(None),
+        };
+        Ok(Some(match args[..] {
+            ["peek", addr, count] => match (addr.parse(), count.parse()) {
+                (Ok(addr), Ok(count)) => Self::Peek(addr, count),
+                _ => bail!(">> Usage: !peek <address> <count>"),
+            },
+            ["peek", ..] => bail!(">> Usage: !peek <address> <count>"),
+            _ => Self::Write(cmd.parse()?),
+        }))
     }
 
-    fn execute(&self, executor: &StaticExecuter) -> anyhow::Result<String> {
-        match self {
+    fn execute(&self, executor: &mut StaticExecuter, ann: &Annotations) -> anyhow::Result<String> {
+        Ok(match self {
             Self::Save(x) => {
                 let replays = serde_json::to_string_pretty(&executor.get_history()).unwrap();
-                match std::fs::File::create(x) {
-                    Ok(mut f) => {
-                        if let Err(x) = f.write_all(replays.as_bytes()) {
-                            bail!(x);
-                        } else {
-                            return Ok(format!(">> Successfully Written To: {:?}", x));
-                        };
-                    },
-                    Err(x) => {
-                        bail!(x);
-                    }
-                }
-            }
-        };
+                std::fs::File::create(x)?.write_all(replays.as_bytes())?;
+                format!(">> Successfully Written To: {:?}", x)
+            },
+            Self::Snapshot(x) => {
+                std::fs::write(x, executor.snapshot().to_json())?;
+                format!(">> Snapshot Written To: {:?}", x)
+            },
+            Self::Xrefs(x) => {
+                let snapshot = executor.snapshot();
+                let index = xrefs::XrefIndex::build(&snapshot.memory, &reverse_engineer::snapshot_roots(&snapshot));
+                index.format(*x, ann)
+            },
+            Self::Peek(addr, count) => {
+                let words: Vec<String> = executor.peek(*addr, *count).iter().map(|w| w.to_string()).collect();
+                format!("{}: {}", addr, words.join(" "))
+            },
+            Self::Write(w) => {
+                executor.write(*w)?;
+                w.to_string()
+            },
+        })
     }
 }
 
@@ -78,8 +495,8 @@ enum Command {
 }
 
 impl Command {
-    fn parse(val: String) -> anyhow::Result<Self> {
-        Ok(match CustomCommand::parse(&val)? {
+    fn parse(val: String, ann: &Annotations) -> anyhow::Result<Self> {
+        Ok(match CustomCommand::parse(&val, ann)? {
             Some(x) => Self::Custom(x),
             None => Self::Game(val)
         })
@@ -192,18 +609,44 @@ impl GameState {
         println!("---------------==================---------------- ");
     }
 }
+/// `checkpoint::replay` input for batch runs
+fn queue_input(env: &mut vm::ExecutionEnv<vm_io::BufferIo>, text: &str) -> anyhow::Result<()> {
+    env.screen.input.extend(text.chars());
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let replay_codes = Args::parse().get_replay()?;
+    let args = Args::parse();
+    let ann = args.get_annotations()?;
+    let patches = args.get_patches()?;
+    if let Some(cmd) = &args.command {
+        return cmd.run(&ann, &patches);
+    }
+    let replay_codes = args.get_replay()?;
+
+    if let Some(port) = args.gdb_port {
+        let (screen, mut user) = vm::Screen::create();
+        let mut env = vm::ExecutionEnv::with_patches(include_bytes!("../challenge.bin"), screen, Some(25734), &patches)?;
+        checkpoint::replay(&mut env, &checkpoint::parse(&replay_codes)?, |_, s| user.send(s.to_string()))?;
+        return gdb_server::GdbServer::new(env, user).serve(port);
+    }
 
     loop {
         let mut game_state = GameState::default();
 
-        // let mut executer = StaticExecuter::new_from_checkpoint(replay_codes.clone())?;
-        let mut executer = StaticExecuter::new();
+        let mut executer = StaticExecuter::new(&patches)?;
+        if let Some(path) = &args.trace {
+            executer.start_trace(path)?;
+        }
         let output = executer.bootstrap()?;
         game_state.update(&output, &mut executer)?;
         print!("{}", output);
         for code in replay_codes.iter() {
+            // Runtime `!reg`/`!poke` writes are replayed where they happened
+            if let Command::Custom(cmd) = Command::parse(code.to_string(), &ann)? {
+                println!(">> {}", cmd.execute(&mut executer, &ann)?);
+                continue;
+            }
             let output = executer.execute(code.to_string())?.unwrap();
             game_state.update(&output, &mut executer)?;
             print!("{}", output);
@@ -214,7 +657,7 @@ fn main() -> anyhow::Result<()> {
         loop {
             let mut cmd = "".into();
             std::io::stdin().read_line(&mut cmd)?;
-            let cmd = match Command::parse(cmd) {
+            let cmd = match Command::parse(cmd, &ann) {
                 Err(x) => {
                     println!(">> ERROR: {x}");
                     continue;
@@ -223,7 +666,7 @@ fn main() -> anyhow::Result<()> {
             };
             match cmd {
                 Command::Custom(cmd) => {
-                    match cmd.execute(&executer) {
+                    match cmd.execute(&mut executer, &ann) {
                         Err(x) => println!(">> ERROR: {x}"),
                         Ok(x)  => println!(">> {x}")
                     };
@@ -243,3 +686,23 @@ fn main() -> anyhow::Result<()> {
         println!("=========== Restarting")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use checkpoint::RuntimeWrite;
+
+    #[test]
+    fn test_custom_commands() {
+        let ann = Annotations::from_json(r#"{"2732": "door"}"#).unwrap();
+        let parse = |s: &str| CustomCommand::parse(s, &ann).unwrap();
+        assert_eq!(parse("!reg r7 25734\n"), Some(CustomCommand::Write(RuntimeWrite::Reg("r7".parse().unwrap(), 25734))));
+        assert_eq!(parse(" !peek 2732 16"), Some(CustomCommand::Peek(2732, 16)));
+        assert_eq!(parse("!poke 2732 0"), Some(CustomCommand::Write(RuntimeWrite::Poke(2732, 0))));
+        assert_eq!(parse("xrefs door\n"), Some(CustomCommand::Xrefs(2732)));
+        assert_eq!(parse("go north\n"), None);
+        for bad in ["!reg r9 1", "!peek 2732", "!poke x 1", "!jump 5", "xrefs nowhere", "xrefs 40000"] {
+            assert!(CustomCommand::parse(bad, &ann).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use std::fmt;
+use std::str::FromStr;
+
 use ux::{u15, u3};
 use anyhow::{bail, Context};
+use crate::error::VmError;
 
 pub trait FillSlice<T> {
     fn copy_first(&mut self, x: &[T]);
@@ -8,11 +12,11 @@ pub trait FillSlice<T> {
 impl FillSlice<u16> for [u16] {
     fn copy_first(&mut self, x: &[u16]) {
         assert!(self.len()>= x.len());
-        self[..x.len()].copy_from_slice(&x);
+        self[..x.len()].copy_from_slice(x);
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Reg(u3);
 
 
@@ -22,14 +26,31 @@ impl From<u3> for Reg {
     }
 }
 
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}", self.to_usize())
+    }
+}
+
+impl FromStr for Reg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let n: u16 = s.strip_prefix('r').and_then(|n| n.parse().ok()).context(format!("Not a register: {}", s))?;
+        match u3::try_from(n) {
+            Ok(r) => Ok(Self(r)),
+            Err(_) => bail!("Not a register: {}", s),
+        }
+    }
+}
+
 impl TryFrom<u16> for Reg {
     type Error = anyhow::Error;
     fn try_from(value: u16) -> anyhow::Result<Self> {
         let u15_max: u16 = u15::MAX.into();
         if value <= u15::MAX.into() {
             bail!("Value is too small to be register: {}", value)
-        } else if value - u15_max -1 >=8 {
-            bail!("Value is too small to be register: {}", value)
+        } else if value - u15_max > 8 {
+            bail!("Value is too large to be register: {}", value)
         }
         let reg_val: u16 = value - u15_max -1;
         let v: u3 = reg_val.try_into().unwrap();
@@ -59,7 +80,7 @@ pub type Num = u15;
 pub type Mem = u15;
 pub type MemBlock = u16;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Addr {
     Reg(Reg), // 0..7
     Mem(Mem), // 15-bit
@@ -76,6 +97,19 @@ impl TryFrom<u16> for Addr {
     }
 }
 
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Val::from(*self).fmt(f)
+    }
+}
+
+impl FromStr for Addr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(s.parse::<Val>()?.into())
+    }
+}
+
 impl From<u15> for Addr {
     fn from(value: u15) -> Self  {
         Self::Mem(value)
@@ -106,12 +140,45 @@ impl IntoUsize for Reg {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Val {
     Reg(Reg),
     Num(Num),
 }
 
+impl Val {
+    /// Memory word for the operand: literal as is, registers as 32768..32775
+    pub fn encode(&self) -> u16 {
+        match self {
+            Self::Num(n) => (*n).into(),
+            Self::Reg(r) => 32768 + r.to_usize() as u16,
+        }
+    }
+}
+
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reg(r) => r.fmt(f),
+            Self::Num(n) => write!(f, "{}", u16::from(*n)),
+        }
+    }
+}
+
+impl FromStr for Val {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.starts_with('r') {
+            return Ok(Self::Reg(s.parse()?));
+        }
+        let n: u16 = s.parse().context(format!("Not a value: {}", s))?;
+        match Num::try_from(n) {
+            Ok(n) => Ok(Self::Num(n)),
+            Err(_) => bail!("Literal out of range: {}", s),
+        }
+    }
+}
+
 impl From<u15> for Val {
     fn from(value: u15) -> Self  {
         Self::Num(value)
@@ -129,14 +196,45 @@ impl TryFrom<u16> for Val {
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         Ok(match TryInto::<Num>::try_into(value){
             Ok(x) => Self::Num(x),
-            Err(v) => {
+            Err(_) => {
                 Self::Reg(value.try_into()?)
             }
         })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Operand `index` of the instruction `words`, converted to whatever the op expects
+fn operand<T: TryFrom<u16>>(words: [u16; 4], index: usize) -> Result<T, VmError> {
+    T::try_from(words[index]).map_err(|_| VmError::InvalidOperand { pc: 0, words, index, value: words[index] })
+}
+
+/// Mnemonic and operand count of every opcode, indexed by opcode
+pub const OPCODES: [(&str, usize); 22] = [
+    ("halt", 0),
+    ("set", 2),
+    ("push", 1),
+    ("pop", 1),
+    ("eq", 3),
+    ("gt", 3),
+    ("jmp", 1),
+    ("jt", 2),
+    ("jf", 2),
+    ("add", 3),
+    ("mult", 3),
+    ("mod", 3),
+    ("and", 3),
+    ("or", 3),
+    ("not", 2),
+    ("rmem", 2),
+    ("wmem", 2),
+    ("call", 1),
+    ("ret", 0),
+    ("out", 1),
+    ("in", 1),
+    ("noop", 0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Op {
     /// 0: stop execution and terminate the program
     Halt,
@@ -219,40 +317,218 @@ impl Op {
     }
 
 
+    /// Assembly mnemonic as used in arch-spec
+    pub fn name(&self) -> &'static str {
+        OPCODES[self.opcode() as usize].0
+    }
+
+    pub fn opcode(&self) -> u16 {
+        match self {
+            Self::Halt => 0,
+            Self::Set(_, _) => 1,
+            Self::Push(_) => 2,
+            Self::Pop(_) => 3,
+            Self::Eq(_, _, _) => 4,
+            Self::Gt(_, _, _) => 5,
+            Self::Jmp(_) => 6,
+            Self::Jt(_, _) => 7,
+            Self::Jf(_, _) => 8,
+            Self::Add(_, _, _) => 9,
+            Self::Mult(_, _, _) => 10,
+            Self::Mod(_, _, _) => 11,
+            Self::And(_, _, _) => 12,
+            Self::Or(_, _, _) => 13,
+            Self::Not(_, _) => 14,
+            Self::Rmem(_, _) => 15,
+            Self::Wmem(_, _) => 16,
+            Self::Call(_) => 17,
+            Self::Ret => 18,
+            Self::Out(_) => 19,
+            Self::In(_) => 20,
+            Self::Noop => 21,
+        }
+    }
+
+    /// Inverse of `parse`: opcode followed by the operand words
+    pub fn encode(&self) -> Vec<u16> {
+        let mut rv = vec![self.opcode()];
+        rv.extend(self.operands().into_iter().map(|v| v.encode()));
+        rv
+    }
+
+    /// Operands in encoding order, addresses are reported as their `Val` counterpart
+    pub fn operands(&self) -> Vec<Val> {
+        match *self {
+            Self::Halt | Self::Ret | Self::Noop => vec![],
+
+            Self::Push(a) | Self::Out(a) | Self::Call(a) => vec![a],
+            Self::Pop(a) | Self::In(a) => vec![a.into()],
+            Self::Jmp(m) => vec![m.into()],
+
+            Self::Set(r, b) => vec![Val::Reg(r), b],
+            Self::Jt(a, m) | Self::Jf(a, m) => vec![a, m.into()],
+            Self::Rmem(a, b) => vec![a.into(), b.into()],
+            Self::Wmem(a, b) | Self::Not(a, b) => vec![a.into(), b],
+
+            Self::Eq(a, b, c)
+            | Self::Gt(a, b, c)
+            | Self::Add(a, b, c)
+            | Self::Mult(a, b, c)
+            | Self::Mod(a, b, c)
+            | Self::And(a, b, c)
+            | Self::Or(a, b, c) => vec![a.into(), b, c],
+        }
+    }
+
+    /// Statically known destination of a jump or call, `None` for register calls
+    pub fn target(&self) -> Option<Mem> {
+        match *self {
+            Self::Jmp(m) | Self::Jt(_, m) | Self::Jf(_, m) => Some(m),
+            Self::Call(Val::Num(m)) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Whether execution may continue with the next instruction (calls are assumed to return)
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Self::Jmp(_) | Self::Ret | Self::Halt)
+    }
+
     pub fn convert_bytes(val: &[u8]) -> Vec<u16> {
         let mut rv = vec![0; val.len()/2];
-        for i in 0..(val.len() / 2) -1 {
+        for i in 0..(val.len() / 2) {
             let val = u16::from_le_bytes([val[i * 2], val[i* 2 + 1]]);
             rv[i] = val;
         }
         rv
     }
 
-    pub fn parse(val: &[u16]) -> anyhow::Result<Self> {
-        return Ok(match val[0] {
+    /// Decode the instruction at the start of `val`. Errors carry pc 0, see `VmError::with_pc`
+    pub fn parse(val: &[u16]) -> Result<Self, VmError> {
+        let mut words = [0u16; 4];
+        let len = val.len().min(4);
+        words[..len].copy_from_slice(&val[..len]);
+        Ok(match words[0] {
             0 => Self::Halt,
-            1 => Self::Set(val[1].try_into()?, val[2].try_into()?),
-            2 => Self::Push(val[1].try_into()?),
-            3 => Self::Pop(val[1].try_into()?),
-            4 => Self::Eq(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            5 => Self::Gt(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            6 => Self::Jmp(val[1].try_into()?),
-            7 => Self::Jt(val[1].try_into()?, val[2].try_into()?),
-            8 => Self::Jf(val[1].try_into()?, val[2].try_into()?),
-            9 => Self::Add(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            10 => Self::Mult(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            11 => Self::Mod(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            12 => Self::And(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            13 => Self::Or(val[1].try_into()?, val[2].try_into()?, val[3].try_into()?),
-            14 => Self::Not(val[1].try_into()?, val[2].try_into()?),
-            15 => Self::Rmem(val[1].try_into()?, val[2].try_into()?),
-            16 => Self::Wmem(val[1].try_into()?, val[2].try_into()?),
-            17 => Self::Call(val[1].try_into()?),
+            1 => Self::Set(operand(words, 1)?, operand(words, 2)?),
+            2 => Self::Push(operand(words, 1)?),
+            3 => Self::Pop(operand(words, 1)?),
+            4 => Self::Eq(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            5 => Self::Gt(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            6 => Self::Jmp(operand(words, 1)?),
+            7 => Self::Jt(operand(words, 1)?, operand(words, 2)?),
+            8 => Self::Jf(operand(words, 1)?, operand(words, 2)?),
+            9 => Self::Add(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            10 => Self::Mult(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            11 => Self::Mod(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            12 => Self::And(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            13 => Self::Or(operand(words, 1)?, operand(words, 2)?, operand(words, 3)?),
+            14 => Self::Not(operand(words, 1)?, operand(words, 2)?),
+            15 => Self::Rmem(operand(words, 1)?, operand(words, 2)?),
+            16 => Self::Wmem(operand(words, 1)?, operand(words, 2)?),
+            17 => Self::Call(operand(words, 1)?),
             18 => Self::Ret,
-            19 => Self::Out(val[1].try_into()?),
-            20 => Self::In(val[1].try_into()?),
+            19 => Self::Out(operand(words, 1)?),
+            20 => Self::In(operand(words, 1)?),
             21 => Self::Noop,
-            _ => bail!("Unknown op code: {}", val[0]),
-        });
+            _ => return Err(VmError::InvalidOpcode { pc: 0, words }),
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())?;
+        for v in self.operands() {
+            write!(f, " {}", v)?;
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Parses `mnemonic a b c`, operands separated by spaces or commas
+impl FromStr for Op {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.split(|c: char| c.is_whitespace() || c == ',').filter(|p| !p.is_empty());
+        let name = parts.next().context("Empty instruction")?;
+        let opcode = OPCODES.iter().position(|(n, _)| *n == name).context(format!("Unknown mnemonic: {}", name))?;
+        let operands: Vec<Val> = parts.map(|p| p.parse()).collect::<anyhow::Result<_>>()?;
+        if operands.len() != OPCODES[opcode].1 {
+            bail!("{} takes {} operands, got {}", name, OPCODES[opcode].1, operands.len());
+        }
+        let mut words = [0u16; 4];
+        words[0] = opcode as u16;
+        for (i, v) in operands.iter().enumerate() {
+            words[i + 1] = v.encode();
+        }
+        Ok(Self::parse(&words)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, quickcheck};
+
+    #[derive(Debug, Clone)]
+    struct AnyOp(Op);
+
+    impl Arbitrary for AnyOp {
+        fn arbitrary(g: &mut Gen) -> Self {
+            loop {
+                let mut words = [u16::arbitrary(g) % 22, 0, 0, 0];
+                for w in words[1..].iter_mut() {
+                    *w = match bool::arbitrary(g) {
+                        true => 32768 + u16::arbitrary(g) % 8,
+                        false => u16::arbitrary(g) % 32768,
+                    };
+                }
+                // Register-only operands reject literals, try again
+                if let Ok(op) = Op::parse(&words) {
+                    return Self(op);
+                }
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_encode_round_trip(op: AnyOp) -> bool {
+            let words = op.0.encode();
+            let mut window = [0u16; 4];
+            window[..words.len()].copy_from_slice(&words);
+            words.len() == op.0.param_bytes() as usize && Op::parse(&window).unwrap() == op.0
+        }
+
+        fn prop_text_round_trip(op: AnyOp) -> bool {
+            op.0.to_string().parse::<Op>().unwrap() == op.0
+        }
+    }
+
+    #[test]
+    fn test_every_opcode() {
+        for opcode in 0..22u16 {
+            // Registers where allowed, `jmp` only takes a literal
+            let words = match opcode {
+                6 => [opcode, 100, 0, 0],
+                _ => [opcode, 32769, 32767, 32775],
+            };
+            let op = Op::parse(&words).unwrap();
+            assert_eq!(op.opcode(), opcode);
+            assert_eq!(op.encode(), words[..op.param_bytes() as usize]);
+            assert_eq!(op.to_string().parse::<Op>().unwrap(), op);
+        }
+        assert_eq!(Op::parse(&[9, 32768, 32775, 5]).unwrap().to_string(), "add r0 r7 5");
+        assert!("set 1 2".parse::<Op>().is_err());
+        assert!("out 32768".parse::<Op>().is_err());
+        assert!("push r8".parse::<Op>().is_err());
+    }
+
+    #[test]
+    fn test_convert_bytes() {
+        assert_eq!(Op::convert_bytes(&[]), Vec::<u16>::new());
+        assert_eq!(Op::convert_bytes(&[1]), Vec::<u16>::new());
+        // The last full word is kept, a trailing odd byte is dropped
+        assert_eq!(Op::convert_bytes(&[1, 0, 0, 1, 7]), vec![1, 256]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{Serialize, Deserialize};
+use crate::op_parser::*;
+
+/// One address-level override of the loaded image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Patch {
+	/// Write the given instructions (e.g. `"set r0 6"`) starting at `addr`
+	Replace { addr: usize, ops: Vec<String> },
+	/// Turn the function at `addr` into `set` of every register followed by `ret`
+	Stub { addr: usize, registers: BTreeMap<String, u16> },
+	/// Write raw words starting at `addr`
+	Poke { addr: usize, words: Vec<u16> },
+}
+
+impl Patch {
+	/// Words written by the patch and where they start
+	pub fn words(&self) -> anyhow::Result<(usize, Vec<u16>)> {
+		Ok(match self {
+			Self::Replace { addr, ops } => {
+				let mut words = vec![];
+				for op in ops {
+					words.extend(op.parse::<Op>()?.encode());
+				}
+				(*addr, words)
+			},
+			Self::Stub { addr, registers } => {
+				let mut words = vec![];
+				for (reg, val) in registers {
+					words.extend(format!("set {} {}", reg, val).parse::<Op>()?.encode());
+				}
+				words.extend(Op::Ret.encode());
+				(*addr, words)
+			},
+			Self::Poke { addr, words } => (*addr, words.clone()),
+		})
+	}
+
+	pub fn apply(&self, memory: &mut [MemBlock]) -> anyhow::Result<()> {
+		let (addr, words) = self.words()?;
+		if addr + words.len() > memory.len() {
+			bail!("Patch at {} of {} words goes past the end of memory", addr, words.len());
+		}
+		memory[addr..addr + words.len()].copy_from_slice(&words);
+		Ok(())
+	}
+}
+
+/// Patches applied to the image at load time, read from a json list like
+/// `[{"type": "stub", "addr": 6027, "registers": {"r0": 6, "r1": 4}}]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PatchSet {
+	pub patches: Vec<Patch>,
+}
+
+impl PatchSet {
+	pub fn from_json(json: &str) -> anyhow::Result<Self> {
+		Ok(serde_json::from_str(json)?)
+	}
+
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let content = std::fs::read_to_string(path)
+			.with_context(|| format!("Reading patches {:?}", path))?;
+		Self::from_json(&content).with_context(|| format!("Parsing patches {:?}", path))
+	}
+
+	pub fn extend(&mut self, other: PatchSet) {
+		self.patches.extend(other.patches);
+	}
+
+	pub fn apply(&self, memory: &mut [MemBlock]) -> anyhow::Result<()> {
+		for (i, p) in self.patches.iter().enumerate() {
+			p.apply(memory).with_context(|| format!("Applying patch #{}", i))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_patches() {
+		let patches = PatchSet::from_json(r#"[
+			{"type": "stub", "addr": 2, "registers": {"r0": 6, "r1": 4}},
+			{"type": "replace", "addr": 0, "ops": ["jmp 2"]},
+			{"type": "poke", "addr": 10, "words": [1, 2]}
+		]"#).unwrap();
+		let mut memory = [0u16; 12];
+		patches.apply(&mut memory).unwrap();
+		assert_eq!(memory, [6, 2, 1, 32768, 6, 1, 32769, 4, 18, 0, 1, 2]);
+
+		let too_far = PatchSet::from_json(r#"[{"type": "poke", "addr": 11, "words": [1, 2]}]"#).unwrap();
+		assert!(too_far.apply(&mut memory).is_err());
+	}
+}
@@ -1,15 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::annotations::Annotations;
 use crate::op_parser::*;
+use crate::vm::EnvSnapshot;
+
+/// Index of the literal operand holding a code or memory address
+fn address_operand(op: &Op) -> Option<usize> {
+	match op {
+		Op::Jmp(_) | Op::Call(Val::Num(_)) | Op::Wmem(Addr::Mem(_), _) => Some(0),
+		Op::Jt(_, _) | Op::Jf(_, _) | Op::Rmem(_, Addr::Mem(_)) => Some(1),
+		_ => None,
+	}
+}
+
+/// `mnemonic op1 op2 ..` without address or raw words, annotated addresses are shown by name
+pub fn fmt_op(op: &Op, ann: &Annotations) -> String {
+	let addr_idx = address_operand(op);
+	let operands: Vec<String> = op.operands().into_iter().enumerate()
+		.map(|(i, v)| match v {
+			Val::Num(n) if Some(i) == addr_idx => ann.symbol(n.to_usize()),
+			_ => v.to_string(),
+		})
+		.collect();
+	if operands.is_empty() {
+		op.name().to_string()
+	} else {
+		format!("{} {}", op.name(), operands.join(" "))
+	}
+}
 
-pub fn parse(content: &[u8]) -> anyhow::Result<String> {
+/// One listing line: address, raw words, mnemonic and operands.
+/// Literal `out` characters and user comments go in a trailing comment.
+pub fn format_instruction(addr: usize, words: &[u16], op: &Op, ann: &Annotations) -> String {
+	let raw: Vec<String> = words.iter().map(|w| format!("{:04x}", w)).collect();
+	let mut line = format!("{:>5}: {:<20} {}", addr, raw.join(" "), fmt_op(op, ann));
+	let mut comments = vec![];
+	if let Op::Out(Val::Num(n)) = op {
+		comments.push(format!("{:?}", u16::from(*n) as u8 as char));
+	}
+	if let Some(c) = ann.comment(addr) {
+		comments.push(c.to_string());
+	}
+	if !comments.is_empty() {
+		let _ = write!(line, "\t; {}", comments.join(" "));
+	}
+	line
+}
+
+/// `name:` line preceding an annotated address
+fn write_label(rv: &mut String, addr: usize, ann: &Annotations) {
+	if let Some(name) = ann.name(addr) {
+		writeln!(rv, "{}:", name).unwrap();
+	}
+}
+
+/// Decode the instruction at `addr`, padding with zeros past the end of memory
+pub fn decode_at(memory: &[u16], addr: usize) -> anyhow::Result<Op> {
+	let mut window = [0u16; 4];
+	let end = memory.len().min(addr + 4);
+	window[..end - addr].copy_from_slice(&memory[addr..end]);
+	Ok(Op::parse(&window).map_err(|e| e.with_pc(addr))?)
+}
+
+/// Linear sweep over `memory`. Words that don't decode are emitted as `???`
+/// and the sweep continues from the next word.
+pub fn disassemble(memory: &[u16], ann: &Annotations) -> String {
 	let mut rv = String::new();
 	let mut i = 0;
-	let mut code_mem = [0u16; 38000];
-	code_mem.copy_first(&Op::convert_bytes(content));
+	while i < memory.len() {
+		write_label(&mut rv, i, ann);
+		match decode_at(memory, i) {
+			Ok(op) => {
+				let end = memory.len().min(i + op.param_bytes() as usize);
+				writeln!(rv, "{}", format_instruction(i, &memory[i..end], &op, ann)).unwrap();
+				i += op.param_bytes() as usize;
+			},
+			Err(_) => {
+				writeln!(rv, "{:>5}: {:<20} ???", i, format!("{:04x}", memory[i])).unwrap();
+				i += 1;
+			}
+		}
+	}
+	rv
+}
+
+/// Recursive descent from `entries`, following static jump and call targets.
+/// Returns every reached instruction keyed by its address.
+pub fn trace_code(memory: &[u16], entries: &[usize]) -> BTreeMap<usize, Op> {
+	let mut code = BTreeMap::new();
+	let mut pending: Vec<usize> = entries.to_vec();
+	while let Some(mut addr) = pending.pop() {
+		while addr < memory.len() && !code.contains_key(&addr) {
+			let op = match decode_at(memory, addr) {
+				Ok(op) => op,
+				Err(_) => break,
+			};
+			code.insert(addr, op);
+			if let Some(t) = op.target() {
+				pending.push(t.to_usize());
+			}
+			if !op.falls_through() {
+				break;
+			}
+			addr += op.param_bytes() as usize;
+		}
+	}
+	code
+}
+
+fn is_text(w: u16) -> bool {
+	(0x20..0x7f).contains(&w) || w == b'\n' as u16
+}
+
+/// Render unreached words as `.ascii` (runs of at least 4 printable chars) and `.word` directives
+fn format_data(rv: &mut String, start: usize, words: &[u16]) {
+	let mut i = 0;
+	while i < words.len() {
+		let text_len = words[i..].iter().take_while(|w| is_text(**w)).count();
+		if text_len >= 4 {
+			let text: String = words[i..i + text_len].iter().map(|w| *w as u8 as char).collect();
+			writeln!(rv, "{:>5}: {:<20} .ascii {:?}", start + i, "", text).unwrap();
+			i += text_len;
+			continue;
+		}
+		let mut end = i;
+		while end < words.len() && end - i < 8 {
+			if words[end..].iter().take_while(|w| is_text(**w)).count() >= 4 {
+				break;
+			}
+			end += 1;
+		}
+		let chunk: Vec<String> = words[i..end].iter().map(|w| w.to_string()).collect();
+		writeln!(rv, "{:>5}: {:<20} .word {}", start + i, "", chunk.join(" ")).unwrap();
+		i = end;
+	}
+}
+
+/// Listing that separates code reached from `entries` from data
+pub fn disassemble_recursive(memory: &[u16], entries: &[usize], ann: &Annotations) -> String {
+	let code = trace_code(memory, entries);
+	let mut rv = String::new();
+	render(&mut rv, memory, &code, &BTreeMap::new(), ann);
+	rv
+}
+
+/// Write the listing for `code`, appending `notes` to the instruction at that address
+fn render(rv: &mut String, memory: &[u16], code: &BTreeMap<usize, Op>, notes: &BTreeMap<usize, String>, ann: &Annotations) {
+	let mut i = 0;
+	while i < memory.len() {
+		write_label(rv, i, ann);
+		match code.get(&i) {
+			Some(op) => {
+				let end = memory.len().min(i + op.param_bytes() as usize);
+				let mut line = format_instruction(i, &memory[i..end], op, ann);
+				if let Some(note) = notes.get(&i) {
+					let _ = write!(line, "\t<== {}", note);
+				}
+				writeln!(rv, "{}", line).unwrap();
+				i = end;
+			},
+			None => {
+				// Data runs stop at the next instruction or labelled address
+				let mut end = code.range(i..).next().map(|(a, _)| *a).unwrap_or(memory.len());
+				end = (i + 1..end).find(|a| ann.name(*a).is_some()).unwrap_or(end);
+				format_data(rv, i, &memory[i..end]);
+				i = end;
+			}
+		}
+	}
+}
+
+/// Stack entries that point right after a `call`, i.e. pending return addresses.
+/// Returned as (stack index, address).
+pub fn return_addresses(memory: &[u16], stack: &[MemBlock]) -> Vec<(usize, usize)> {
+	stack.iter().enumerate()
+		.map(|(i, v)| (i, *v as usize))
+		.filter(|(_, v)| *v >= 2 && *v < memory.len() && memory[*v - 2] == 17)
+		.collect()
+}
+
+/// Known code addresses of a running program: address 0, the pc and pending return addresses
+pub fn snapshot_roots(snapshot: &EnvSnapshot) -> Vec<usize> {
+	let mut roots = vec![0, snapshot.curr_point as usize];
+	roots.extend(return_addresses(&snapshot.memory, &snapshot.stack).into_iter().map(|(_, a)| a));
+	roots
+}
+
+/// Listing of memory as it is in `snapshot`. Runtime-modified code is decoded as it
+/// currently is, and the current instruction and pending return addresses are marked.
+pub fn disassemble_snapshot(snapshot: &EnvSnapshot, ann: &Annotations) -> String {
+	let memory = &snapshot.memory;
+	let returns = return_addresses(memory, &snapshot.stack);
+	let pc = snapshot.curr_point as usize;
+
+	let code = trace_code(memory, &snapshot_roots(snapshot));
+
+	let mut notes = BTreeMap::new();
+	for (i, addr) in returns.iter() {
+		notes.insert(*addr, format!("ret (stack[{}])", i));
+	}
+	notes.insert(pc, "pc".to_string());
+
+	let mut rv = String::new();
+	let regs: Vec<String> = snapshot.registers.iter().enumerate()
+		.map(|(i, r)| format!("r{}={}", i, r))
+		.collect();
+	writeln!(rv, "; pc {}  {}", pc, regs.join(" ")).unwrap();
+	writeln!(rv, "; stack {:?}", snapshot.stack).unwrap();
+	render(&mut rv, memory, &code, &notes, ann);
+	rv
+}
+
+pub fn parse(content: &[u8], ann: &Annotations) -> anyhow::Result<String> {
+	Ok(disassemble(&Op::convert_bytes(content), ann))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_linear_listing() {
+		// set r0 4; out 'A'; halt
+		let mem = [1, 32768, 4, 19, 65, 0];
+		let listing = disassemble(&mem, &Annotations::default());
+		let lines: Vec<&str> = listing.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert!(lines[0].starts_with("    0: 0001 8000 0004"));
+		assert!(lines[0].ends_with("set r0 4"));
+		assert!(lines[1].contains("out 65\t; 'A'"));
+		assert!(lines[2].starts_with("    5:"));
+		assert!(lines[2].ends_with("halt"));
+	}
+
+	#[test]
+	fn test_recursive_skips_data() {
+		// jmp 6; "Hi!!"; out r1; halt
+		let mem = [6, 6, 72, 105, 33, 33, 19, 32769, 0];
+		let listing = disassemble_recursive(&mem, &[0], &Annotations::default());
+		let lines: Vec<&str> = listing.lines().collect();
+		assert_eq!(lines.len(), 4);
+		assert!(lines[0].ends_with("jmp 6"));
+		assert!(lines[1].ends_with(".ascii \"Hi!!\""));
+		assert!(lines[2].ends_with("out r1"));
+		assert!(lines[3].ends_with("halt"));
+	}
 
-	while i < content.len() {
-		let op = Op::parse(&code_mem[i..i+4])?;
-		dbg!(op);
-		i += op.param_bytes() as usize;
+	#[test]
+	fn test_annotated_listing() {
+		// call 4; halt; noop; ret
+		let mem = [17, 4, 0, 21, 18];
+		let ann = Annotations::from_json(r#"{"4": {"name": "helper", "comment": "does nothing"}}"#).unwrap();
+		let listing = disassemble_recursive(&mem, &[0], &ann);
+		let lines: Vec<&str> = listing.lines().collect();
+		assert!(lines[0].ends_with("call helper"));
+		assert_eq!(lines[3], "helper:");
+		assert!(lines[4].ends_with("ret\t; does nothing"));
 	}
-	Ok(rv)
-}
\ No newline at end of file
+}
@@ -0,0 +1,323 @@
+use std::io::{self, Read, Write};
+
+use crate::annotations::Annotations;
+use crate::op_parser::*;
+use crate::reverse_engineer::fmt_op;
+use crate::vm::ExecutionEnv;
+use crate::vm_io::Io;
+
+const MAGIC: &[u8; 8] = b"SYNTRACE";
+const VERSION: u16 = 1;
+
+/// One executed instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// `operation_count` after the instruction
+    pub op_count: u64,
+    pub pc: usize,
+    /// The instruction as decoded from memory (hooks may have replaced what actually ran)
+    pub op: Op,
+    /// Operand values before the instruction ran, registers resolved
+    pub values: Vec<u16>,
+    /// Registers the instruction changed, with their new values
+    pub reg_deltas: Vec<(usize, u16)>,
+    /// Stack depth after the instruction
+    pub stack_depth: usize,
+}
+
+fn write_varint(out: &mut impl Write, mut v: u64) -> io::Result<()> {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn write_u16(out: &mut impl Write, v: u16) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+/// Writes records in the binary trace format:
+///
+/// - header: `SYNTRACE`, version as u16
+/// - per record: op count delta (LEB128), pc, opcode (u8), raw operand words, resolved
+///   operand values, changed register mask (u8) followed by the new values, stack depth
+///
+/// Numbers are little-endian u16 unless noted. Like `BufWriter`, the first write error is
+/// kept and reported by `flush` or `finish`.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    prev_count: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        write_u16(&mut out, VERSION)?;
+        Ok(Self { out, prev_count: 0, error: None })
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            if let Err(e) = self.write_record(record) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write_record(&mut self, r: &TraceRecord) -> io::Result<()> {
+        write_varint(&mut self.out, r.op_count - self.prev_count)?;
+        self.prev_count = r.op_count;
+        write_u16(&mut self.out, r.pc as u16)?;
+        let words = r.op.encode();
+        self.out.write_all(&[words[0] as u8])?;
+        for w in words[1..].iter().chain(r.values.iter()) {
+            write_u16(&mut self.out, *w)?;
+        }
+        let mask = r.reg_deltas.iter().fold(0u8, |m, (reg, _)| m | 1 << reg);
+        self.out.write_all(&[mask])?;
+        for (_, v) in r.reg_deltas.iter() {
+            write_u16(&mut self.out, *v)?;
+        }
+        write_u16(&mut self.out, r.stack_depth as u16)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Iterates over the records of a binary trace
+pub struct TraceReader<R: Read> {
+    input: R,
+    count: u64,
+}
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a trace file".into()));
+        }
+        let version = read_u16(&mut input)?;
+        if version != VERSION {
+            return Err(invalid(format!("Unsupported trace version {}", version)));
+        }
+        Ok(Self { input, count: 0 })
+    }
+
+    /// The next record, `None` at a clean end of the file
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut delta = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8; 1];
+            if self.input.read(&mut byte)? == 0 {
+                if shift == 0 {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            delta |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        self.count += delta;
+        let pc = read_u16(&mut self.input)? as usize;
+        let mut opcode = [0u8; 1];
+        self.input.read_exact(&mut opcode)?;
+        let arity = OPCODES.get(opcode[0] as usize)
+            .ok_or_else(|| invalid(format!("Unknown op code {} in trace", opcode[0])))?.1;
+        let mut words = [opcode[0] as u16, 0, 0, 0];
+        for w in words[1..=arity].iter_mut() {
+            *w = read_u16(&mut self.input)?;
+        }
+        let op = Op::parse(&words).map_err(|e| invalid(e.with_pc(pc).to_string()))?;
+        let values = (0..arity).map(|_| read_u16(&mut self.input)).collect::<io::Result<_>>()?;
+        let mut mask = [0u8; 1];
+        self.input.read_exact(&mut mask)?;
+        let mut reg_deltas = vec![];
+        for reg in 0..8 {
+            if mask[0] & 1 << reg != 0 {
+                reg_deltas.push((reg, read_u16(&mut self.input)?));
+            }
+        }
+        let stack_depth = read_u16(&mut self.input)? as usize;
+        Ok(Some(TraceRecord { op_count: self.count, pc, op, values, reg_deltas, stack_depth }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+impl<S: Io> ExecutionEnv<S> {
+    /// Write a record for every executed instruction to `out`, ending any running trace
+    pub fn start_trace(&mut self, out: Box<dyn Write + Send>) -> io::Result<()> {
+        self.stop_trace()?;
+        self.tracer = Some(TraceWriter::new(out)?);
+        Ok(())
+    }
+
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(tracer) => tracer.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which records `dump` prints
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Inclusive pc range
+    pub range: Option<(usize, usize)>,
+    /// Opcodes to keep, all if empty
+    pub opcodes: Vec<u16>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, r: &TraceRecord) -> bool {
+        if let Some((start, end)) = self.range {
+            if r.pc < start || r.pc > end {
+                return false;
+            }
+        }
+        self.opcodes.is_empty() || self.opcodes.contains(&r.op.opcode())
+    }
+}
+
+/// One line per record: op count, pc, instruction, register operand values,
+/// changed registers and stack depth. The pc and jump or call targets show their names
+pub fn format_record(r: &TraceRecord, ann: &Annotations) -> String {
+    let reads: Vec<String> = r.op.operands().iter().zip(r.values.iter())
+        .filter_map(|(o, v)| match o {
+            Val::Reg(reg) => Some(format!("{}={}", reg, v)),
+            Val::Num(_) => None,
+        })
+        .collect();
+    let writes: Vec<String> = r.reg_deltas.iter().map(|(reg, v)| format!("r{}={}", reg, v)).collect();
+    format!("{:>10} {:>5}: {:<24} ; {:<24} => {:<24} sp={}",
+        r.op_count, ann.symbol(r.pc), fmt_op(&r.op, ann), reads.join(" "), writes.join(" "), r.stack_depth)
+}
+
+/// Text dump of the records of `reader` that pass `filter`
+pub fn dump<R: Read>(reader: TraceReader<R>, filter: &TraceFilter, ann: &Annotations, out: &mut impl Write) -> anyhow::Result<()> {
+    for r in reader {
+        let r = r?;
+        if filter.matches(&r) {
+            writeln!(out, "{}", format_record(&r, ann).trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::vm_io::BufferIo;
+
+    /// Bytes written through any clone, as `start_trace` keeps its writer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The trace of running `env` until it halts or needs input
+    pub(crate) fn trace_run<S: Io>(env: &mut ExecutionEnv<S>) -> Vec<u8> {
+        let buf = SharedBuf::default();
+        env.start_trace(Box::new(buf.clone())).unwrap();
+        env.run().unwrap();
+        env.stop_trace().unwrap();
+        // The env dropped its clone with the writer
+        Arc::into_inner(buf.0).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let words = assemble("
+            set r0 2
+            loop: push r0
+            add r0 r0 32767
+            jt r0 loop
+            out 'x'
+            halt
+        ").unwrap();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), BufferIo::new(""), None);
+        let trace = trace_run(&mut env);
+
+        let records: Vec<TraceRecord> = TraceReader::new(&trace[..]).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 9);
+        assert_eq!(records[2], TraceRecord {
+            op_count: 3,
+            pc: 5,
+            op: "add r0 r0 32767".parse().unwrap(),
+            values: vec![2, 2, 32767],
+            reg_deltas: vec![(0, 1)],
+            stack_depth: 1,
+        });
+        assert_eq!(records[8].op, Op::Halt);
+
+        let filter = TraceFilter { range: Some((3, 9)), opcodes: vec![9] };
+        let mut text = vec![];
+        dump(TraceReader::new(&trace[..]).unwrap(), &filter, &Annotations::default(), &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.lines().nth(1).unwrap().contains("add r0 r0 32767          ; r0=1"));
+
+        // Names for the pc and the jump target
+        let ann = Annotations::from_json(r#"{"3": "loop"}"#).unwrap();
+        let filter = TraceFilter { range: None, opcodes: vec![2, 7] };
+        let mut text = vec![];
+        dump(TraceReader::new(&trace[..]).unwrap(), &filter, &ann, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.lines().next().unwrap().contains(" loop: push r0 "));
+        assert!(text.lines().nth(1).unwrap().contains("    9: jt r0 loop "));
+
+        assert!(TraceReader::new(&b"SYNTRACX\x01\x00"[..]).is_err());
+        assert!(TraceReader::new(&trace[..trace.len() - 1]).unwrap().any(|r| r.is_err()));
+    }
+}
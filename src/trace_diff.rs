@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+
+use crate::annotations::Annotations;
+use crate::op_parser::*;
+use crate::trace::{format_record, TraceRecord};
+
+/// Records after a divergence searched for where the traces line up again
+const RESYNC_WINDOW: usize = 1000;
+/// Records that must line up in a row to count as back in sync
+const RESYNC_RUN: usize = 4;
+
+/// Register values as far as a trace shows them: what register operands read
+/// and what instructions wrote. Registers set outside of instructions (the preset
+/// hook, patches) are only seen once something reads them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Registers([Option<MemBlock>; 8]);
+
+impl Registers {
+    fn observe(&mut self, r: &TraceRecord) {
+        for (o, v) in r.op.operands().iter().zip(r.values.iter()) {
+            if let Val::Reg(reg) = o {
+                self.0[reg.to_usize()] = Some(*v);
+            }
+        }
+    }
+
+    fn apply(&mut self, r: &TraceRecord) {
+        for (reg, v) in r.reg_deltas.iter() {
+            self.0[*reg] = Some(*v);
+        }
+    }
+}
+
+/// Where two traces stop doing the same thing
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Index of the divergent record in both traces
+    pub index: usize,
+    /// `None` when that trace ended
+    pub left: Option<TraceRecord>,
+    pub right: Option<TraceRecord>,
+    /// Registers that differ after the divergent instruction, (register, left, right)
+    pub registers: Vec<(usize, Option<MemBlock>, Option<MemBlock>)>,
+    /// Common records leading up to the divergence
+    pub before: Vec<TraceRecord>,
+    /// Records following the divergent one in each trace
+    pub left_after: Vec<TraceRecord>,
+    pub right_after: Vec<TraceRecord>,
+    /// Indices where the traces run the same instructions again after one of them took
+    /// a detour, `None` if control flow didn't split or didn't rejoin within `RESYNC_WINDOW`
+    pub resync: Option<(usize, usize)>,
+}
+
+/// Whether two records did the same thing. The op count is left out as hooks
+/// running a different number of instructions shouldn't count as a divergence.
+fn same(a: &TraceRecord, b: &TraceRecord) -> bool {
+    a.pc == b.pc && a.op == b.op && a.values == b.values
+        && a.reg_deltas == b.reg_deltas && a.stack_depth == b.stack_depth
+}
+
+/// Where `left` and `right`, both starting at a divergence, go through the same pcs at
+/// the same stack depth again once their control flow split. The offsets skipping the
+/// fewest records in total win.
+fn resync(left: &[TraceRecord], right: &[TraceRecord]) -> Option<(usize, usize)> {
+    let key = |r: &TraceRecord| (r.pc, r.stack_depth);
+    let aligned = |i: usize, j: usize| (0..RESYNC_RUN).all(|k| match (left.get(i + k), right.get(j + k)) {
+        (Some(a), Some(b)) => key(a) == key(b),
+        _ => false,
+    });
+    // A divergence in values only leaves the control flow in step for a while
+    let split = left.iter().zip(right.iter()).take_while(|(a, b)| key(a) == key(b)).count();
+    let remaining = (left.len() - split) + (right.len() - split);
+    (1..=remaining)
+        .flat_map(|skipped| (0..=skipped).map(move |i| (split + i, split + skipped - i)))
+        .find(|(i, j)| aligned(*i, *j))
+}
+
+/// Walk two traces in lockstep and return the first record where they differ,
+/// with `context` records of context on each side. `None` if they are identical.
+/// Past the divergence, the traces are aligned to find where they rejoin.
+pub fn first_divergence<L, R>(mut left: L, mut right: R, context: usize) -> io::Result<Option<Divergence>>
+where
+    L: Iterator<Item = io::Result<TraceRecord>>,
+    R: Iterator<Item = io::Result<TraceRecord>>,
+{
+    let mut before = VecDeque::new();
+    let mut regs = (Registers::default(), Registers::default());
+    let mut index = 0;
+    loop {
+        let (l, r) = (left.next().transpose()?, right.next().transpose()?);
+        for (regs, rec) in [(&mut regs.0, &l), (&mut regs.1, &r)] {
+            if let Some(rec) = rec {
+                regs.observe(rec);
+                regs.apply(rec);
+            }
+        }
+        match (l, r) {
+            (None, None) => return Ok(None),
+            (Some(a), Some(b)) if same(&a, &b) => {
+                before.push_back(a);
+                if before.len() > context {
+                    before.pop_front();
+                }
+                index += 1;
+            },
+            (l, r) => {
+                let registers = (0..8)
+                    .filter(|i| regs.0.0[*i] != regs.1.0[*i])
+                    .map(|i| (i, regs.0.0[i], regs.1.0[i]))
+                    .collect();
+                let left_rest: Vec<TraceRecord> = left.take(RESYNC_WINDOW).collect::<io::Result<_>>()?;
+                let right_rest: Vec<TraceRecord> = right.take(RESYNC_WINDOW).collect::<io::Result<_>>()?;
+                let from_divergence = |first: &Option<TraceRecord>, rest: &[TraceRecord]| -> Vec<TraceRecord> {
+                    first.iter().chain(rest.iter()).cloned().collect()
+                };
+                let resync = resync(&from_divergence(&l, &left_rest), &from_divergence(&r, &right_rest))
+                    .map(|(i, j)| (index + i, index + j));
+                return Ok(Some(Divergence {
+                    index,
+                    left: l,
+                    right: r,
+                    registers,
+                    before: before.into(),
+                    left_after: left_rest.into_iter().take(context).collect(),
+                    right_after: right_rest.into_iter().take(context).collect(),
+                    resync,
+                }));
+            }
+        }
+    }
+}
+
+impl Divergence {
+    /// Report in the `trace-dump` line format, left lines marked `-` and right ones `+`
+    pub fn format(&self, ann: &Annotations) -> String {
+        let mut rv = String::new();
+        writeln!(rv, "First divergence at instruction #{}", self.index).unwrap();
+        for r in self.before.iter() {
+            writeln!(rv, "  {}", format_record(r, ann).trim_end()).unwrap();
+        }
+        for (mark, rec) in [('-', &self.left), ('+', &self.right)] {
+            match rec {
+                Some(r) => writeln!(rv, "{} {}", mark, format_record(r, ann).trim_end()).unwrap(),
+                None => writeln!(rv, "{} <end of trace>", mark).unwrap(),
+            }
+        }
+        if !self.registers.is_empty() {
+            let regs: Vec<String> = self.registers.iter()
+                .map(|(i, l, r)| format!("r{}: {} / {}", i, show(*l), show(*r)))
+                .collect();
+            writeln!(rv, "Registers differing: {}", regs.join(", ")).unwrap();
+        }
+        for (mark, after) in [('-', &self.left_after), ('+', &self.right_after)] {
+            for r in after.iter() {
+                writeln!(rv, "{} {}", mark, format_record(r, ann).trim_end()).unwrap();
+            }
+        }
+        if let Some((l, r)) = self.resync {
+            writeln!(rv, "Traces line up again at instruction #{} / #{}", l, r).unwrap();
+        }
+        rv
+    }
+}
+
+fn show(v: Option<MemBlock>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_else(|| "?".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::trace::tests::trace_run;
+    use crate::trace::{TraceReader, TraceWriter};
+
+    fn trace(records: &[TraceRecord]) -> Vec<u8> {
+        let mut writer = TraceWriter::new(vec![]).unwrap();
+        for r in records {
+            writer.write(r);
+        }
+        writer.finish().unwrap()
+    }
+
+    fn run(input: &str) -> Vec<TraceRecord> {
+        let words = assemble("
+            set r2 5
+            push r2
+            in r0
+            eq r1 r0 'a'
+            jt r1 done
+            out r0
+            done: noop
+            noop
+            noop
+            halt
+        ").unwrap();
+        let mut env = crate::vm::ExecutionEnv::new(&to_bytes(&words), crate::vm_io::BufferIo::new(input), None);
+        let bytes = trace_run(&mut env);
+        TraceReader::new(&bytes[..]).unwrap().map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let (a, b) = (trace(&run("a")), trace(&run("b")));
+        fn read(t: &[u8]) -> TraceReader<&[u8]> {
+            TraceReader::new(t).unwrap()
+        }
+        assert!(first_divergence(read(&a), read(&a), 3).unwrap().is_none());
+
+        let d = first_divergence(read(&a), read(&b), 1).unwrap().unwrap();
+        assert_eq!(d.index, 2);
+        assert_eq!(d.left.as_ref().unwrap().reg_deltas, vec![(0, 'a' as u16)]);
+        assert_eq!(d.registers, vec![(0, Some('a' as u16), Some('b' as u16))]);
+        assert_eq!(d.before.len(), 1);
+        assert_eq!(d.before[0].op, "push r2".parse().unwrap());
+        assert_eq!(d.right_after[0].op.name(), "eq");
+        assert!(d.format(&Annotations::default()).contains("Registers differing: r0: 97 / 98"));
+        // The `out` taken on the right is skipped to line up the `noop`s
+        assert_eq!(d.resync, Some((5, 6)));
+        assert!(d.format(&Annotations::default()).ends_with("Traces line up again at instruction #5 / #6\n"));
+
+        // One trace stopping early
+        let d = first_divergence(read(&a), read(&trace(&run("a")[..4])), 2).unwrap().unwrap();
+        assert_eq!((d.index, d.right.is_none(), d.resync), (4, true, None));
+        assert!(d.format(&Annotations::default()).contains("+ <end of trace>"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use crate::op_parser::*;
+use crate::vm::{EnvSnapshot, ExecutionEnv};
+use crate::vm_io::Io;
+
+/// A mutation done by an instruction, holding what is needed to revert it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Memory word and its old value
+    Mem(usize, MemBlock),
+    Push,
+    /// Value that was popped
+    Pop(MemBlock),
+    /// Char read by `in`, handed back to the screen on undo
+    Input(char),
+}
+
+/// State before one executed instruction, or before a write from outside the program
+/// (see `ExecutionEnv::poke`). Registers are small enough to keep whole, which also
+/// covers changes done by hooks.
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    pc: Mem,
+    operation_count: u64,
+    registers: [MemBlock; 8],
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UndoConfig {
+    /// Instructions between keyframes
+    pub keyframe_interval: u64,
+    /// Keyframes kept, history older than the oldest one is dropped
+    pub max_keyframes: usize,
+}
+
+impl Default for UndoConfig {
+    fn default() -> Self {
+        Self { keyframe_interval: 10_000, max_keyframes: 32 }
+    }
+}
+
+/// Undo log of the instructions executed since recording started. Every `keyframe_interval`
+/// instructions a full `EnvSnapshot` is kept, which bounds memory use (history before the
+/// oldest keyframe is dropped) and makes long jumps back cheap.
+pub struct UndoLog {
+    config: UndoConfig,
+    entries: VecDeque<UndoEntry>,
+    /// Absolute index of `entries[0]`
+    first: u64,
+    /// Snapshots taken before the entry with the given absolute index
+    keyframes: VecDeque<(u64, EnvSnapshot)>,
+}
+
+impl UndoLog {
+    pub fn new(config: UndoConfig) -> Self {
+        Self { config, entries: VecDeque::new(), first: 0, keyframes: VecDeque::new() }
+    }
+
+    /// Instructions that can be stepped back
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn end(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+
+    /// Start the entry of the instruction about to execute in `env`
+    pub(crate) fn begin<S: Io>(&mut self, env: &ExecutionEnv<S>) {
+        let index = self.end();
+        let due = match self.keyframes.back() {
+            Some(k) => index - k.0 >= self.config.keyframe_interval,
+            None => true,
+        };
+        if due {
+            self.keyframes.push_back((index, env.snapshot()));
+            if self.keyframes.len() > self.config.max_keyframes.max(1) {
+                self.keyframes.pop_front();
+                let oldest = self.keyframes[0].0;
+                self.entries.drain(..(oldest - self.first) as usize);
+                self.first = oldest;
+            }
+        }
+        self.entries.push_back(UndoEntry {
+            pc: env.curr_point,
+            operation_count: env.operation_count,
+            registers: env.registers,
+            changes: vec![],
+        });
+    }
+
+    /// Drop the entry started last, for instructions that didn't execute after all
+    pub(crate) fn cancel(&mut self) {
+        self.entries.pop_back();
+        let end = self.end();
+        while self.keyframes.back().map(|k| k.0 >= end).unwrap_or(false) {
+            self.keyframes.pop_back();
+        }
+    }
+
+    pub(crate) fn record(&mut self, change: Change) {
+        if let Some(e) = self.entries.back_mut() {
+            e.changes.push(change);
+        }
+    }
+}
+
+impl<S: Io> ExecutionEnv<S> {
+    /// Start or stop recording the undo log. Stopping drops the recorded history
+    pub fn record_undo(&mut self, config: Option<UndoConfig>) {
+        self.undo = config.map(UndoLog::new);
+    }
+
+    /// Revert the last `n` executed instructions or outside writes, returns how many could
+    /// be reverted. Input read by reverted `in`s is handed back to the screen, output is not
+    /// taken back.
+    pub fn step_back(&mut self, n: usize) -> usize {
+        let mut log = match self.undo.take() {
+            Some(log) => log,
+            None => return 0,
+        };
+        let n = n.min(log.len());
+        let target = log.end() - n as u64;
+
+        // Jump to the earliest keyframe past the target, then undo the rest one by one
+        if let Some(pos) = log.keyframes.iter().position(|k| k.0 >= target) {
+            let (index, snapshot) = &log.keyframes[pos];
+            let from = (index - log.first) as usize;
+            for e in log.entries.range(from..).rev() {
+                self.unget_inputs(e);
+            }
+            self.restore(snapshot);
+            log.entries.truncate(from);
+            log.keyframes.truncate(pos);
+        }
+        while log.end() > target {
+            let e = log.entries.pop_back().unwrap();
+            self.unget_inputs(&e);
+            for change in e.changes.iter().rev() {
+                match *change {
+                    Change::Mem(addr, old) => self.write_mem(addr.try_into().unwrap(), old),
+                    Change::Push => { self.stack.pop(); },
+                    Change::Pop(v) => self.stack.push(v),
+                    Change::Input(_) => {},
+                }
+            }
+            self.registers = e.registers;
+            self.curr_point = e.pc;
+            self.operation_count = e.operation_count;
+        }
+        self.undo = Some(log);
+        n
+    }
+
+    /// Step back until `done` holds, at least one step.
+    /// Returns false if the history ran out first
+    pub fn run_back_until(&mut self, mut done: impl FnMut(&ExecutionEnv<S>) -> bool) -> bool {
+        loop {
+            if self.step_back(1) == 0 {
+                return false;
+            }
+            if done(self) {
+                return true;
+            }
+        }
+    }
+
+    fn unget_inputs(&mut self, e: &UndoEntry) {
+        for change in e.changes.iter().rev() {
+            if let Change::Input(c) = change {
+                self.screen.unget_char(*c);
+            }
+        }
+    }
+
+    fn restore(&mut self, snapshot: &EnvSnapshot) {
+        self.stack = snapshot.stack.clone();
+        self.memory.copy_from_slice(&snapshot.memory);
+        self.registers = snapshot.registers;
+        self.curr_point = snapshot.curr_point.try_into().unwrap();
+        self.operation_count = snapshot.operation_count;
+        self.set_decode_cache(self.decode_cache_enabled());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::vm_io::BufferIo;
+
+    fn env(config: UndoConfig) -> ExecutionEnv<BufferIo> {
+        let words = assemble("
+            set r0 3
+            loop: push r0
+            in r1
+            wmem 100 r1
+            add r0 r0 32767
+            jt r0 loop
+            pop r2
+            halt
+        ").unwrap();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), BufferIo::new("abc"), None);
+        env.record_undo(Some(config));
+        env
+    }
+
+    #[test]
+    fn test_step_back() {
+        for config in [UndoConfig::default(), UndoConfig { keyframe_interval: 3, max_keyframes: 100 }] {
+            let mut env = env(config);
+            let start = env.snapshot().to_json();
+            let mut states = vec![start.clone()];
+            while !env.step().unwrap().halted {
+                states.push(env.snapshot().to_json());
+            }
+            // The halt was executed too
+            assert_eq!(env.step_back(1), 1);
+            assert_eq!(env.snapshot().to_json(), *states.last().unwrap());
+            assert_eq!(env.step_back(5), 5);
+            assert_eq!(env.snapshot().to_json(), states[states.len() - 6]);
+            // Back to the `in` of the second iteration
+            assert!(env.run_back_until(|env| env.curr_point.to_usize() == 5));
+            assert_eq!(env.operation_count, 7);
+            assert_eq!(env.registers[1], 'a' as u16);
+            assert_eq!(env.screen.input, ['b', 'c']);
+            assert_eq!(env.step_back(100), 7);
+            assert_eq!(env.snapshot().to_json(), start);
+            assert_eq!(env.screen.input, ['a', 'b', 'c']);
+            assert!(!env.run_back_until(|env| env.curr_point.to_usize() == 5));
+
+            // Running again gives the same result
+            env.run().unwrap();
+            assert_eq!((env.memory[100], env.registers[2]), ('c' as u16, 1));
+        }
+    }
+
+    #[test]
+    fn test_outside_writes() {
+        let mut env = env(UndoConfig::default());
+        env.run_for(4, |_, _| {}).unwrap();
+        env.poke(100u16.try_into().unwrap(), 7);
+        env.set_register(2, 9);
+        // Each write is a step of its own, the instruction before keeps its effect
+        assert_eq!(env.step_back(1), 1);
+        assert_eq!((env.memory[100], env.registers[2]), (7, 0));
+        assert_eq!(env.step_back(1), 1);
+        assert_eq!((env.memory[100], env.operation_count), ('a' as u16, 4));
+        assert!(env.run_back_until(|env| env.operation_count == 0));
+    }
+
+    #[test]
+    fn test_bounded_history() {
+        let mut env = env(UndoConfig { keyframe_interval: 4, max_keyframes: 2 });
+        env.run().unwrap();
+        // 18 instructions ran, only those since the keyframe at 12 are kept
+        assert_eq!(env.undo.as_ref().unwrap().len(), 6);
+        assert_eq!(env.step_back(10), 6);
+        assert_eq!(env.operation_count, 12);
+    }
+}
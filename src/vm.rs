@@ -1,15 +1,18 @@
-use core::num;
-use std::collections::{VecDeque, HashSet, BTreeSet};
 use std::sync::mpsc::TryRecvError;
-use std::{ops::Mul, sync::mpsc::Receiver};
 use std::fs::File;
 use std::io::prelude::*;
 use serde::{Serialize, Deserialize};
-use ux::{u15, u3};
+use ux::u15;
 use anyhow::{bail, Context};
 use crate::op_parser::*;
-
-const DEBUG_PRINT: bool = true;
+use crate::patch::PatchSet;
+use crate::checkpoint::RuntimeWrite;
+use crate::hooks::{self, HookAction, Hooks, Trigger};
+use crate::error::VmError;
+use crate::vm_io::Io;
+use crate::undo::{Change, UndoLog};
+use crate::watch::{Access, Location, Watchpoints};
+use crate::trace::{TraceRecord, TraceWriter};
 
 fn wrapping_mul(a: Num, b: Num) -> Num {
     let a_u16: u64 = a.into();
@@ -47,11 +50,11 @@ pub struct EnvSnapshot {
 }
 
 impl EnvSnapshot {
-	pub fn new(env: &ExecutionEnv) -> Self {
+	pub fn new<S: Io>(env: &ExecutionEnv<S>) -> Self {
 		Self {
 			stack: env.stack.clone(),
 			memory: Vec::from_iter(env.memory.iter().cloned()),
-			registers: env.registers.clone(),
+			registers: env.registers,
 			curr_point: env.curr_point.into(),
 			register_8_preset: env.register_8_preset,
             operation_count: env.operation_count
@@ -63,27 +66,49 @@ impl EnvSnapshot {
 	pub fn from_json(json: &str) -> anyhow::Result<Self> {
 		Ok(serde_json::from_str(json)?)
 	}
-	pub fn to_env(&self, screen: Screen) -> anyhow::Result<ExecutionEnv> {
-		Ok(ExecutionEnv {
-			stack: self.stack.clone(),
-			memory: self.memory.clone().try_into().ok().context("memory length not correct")?,
-			registers: self.registers.clone(),
-			curr_point: self.curr_point.try_into()?,
-			screen: screen,
-			register_8_preset: self.register_8_preset,
-            operation_count: self.operation_count
-		})
-	}
 }
 
-pub struct ExecutionEnv {
+pub struct ExecutionEnv<S: Io = Screen> {
     pub(crate) stack: Vec<MemBlock>, // 
     pub(crate) memory: [MemBlock; 32768], // [code] 32768
     pub(crate) registers: [MemBlock; 8],  //    (1)
     pub(crate) curr_point: Mem,
-    pub(crate) screen: Screen,
+    pub(crate) screen: S,
 	pub(crate) register_8_preset: Option<u16>,
-    pub(crate) operation_count: u64
+    pub(crate) operation_count: u64,
+	pub(crate) hooks: Hooks<S>,
+	/// Where the last `HookAction::Stop` left the pc, hooks are skipped once when resuming there
+	stopped_at: Option<Mem>,
+	/// Decoded instructions by address, `None` when caching is off.
+	/// Memory writes must go through `poke` to keep it valid
+	decoded: Option<Vec<Option<Op>>>,
+	/// Undo log, when recording (see `record_undo`)
+	pub(crate) undo: Option<UndoLog>,
+	pub(crate) watches: Watchpoints,
+	/// Trace of executed instructions, when recording (see `start_trace`)
+	pub(crate) tracer: Option<TraceWriter<Box<dyn Write + Send>>>
+}
+
+/// What a single `ExecutionEnv::step` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepOutcome {
+    /// The decoded instruction at `pc_before`
+    pub op: Op,
+    pub pc_before: usize,
+    pub pc_after: usize,
+    /// `halt` or `ret` with an empty stack
+    pub halted: bool,
+    /// The op is an `in` and there is no input, nothing was executed
+    pub waiting_for_input: bool,
+    /// A hook stopped the instruction before it executed, or it hit a watchpoint
+    pub stopped: bool,
+}
+
+impl StepOutcome {
+    /// Whether running on would not make progress without outside help
+    pub fn is_final(&self) -> bool {
+        self.halted || self.waiting_for_input || self.stopped
+    }
 }
 
 pub struct Screen {
@@ -96,7 +121,7 @@ impl Screen {
     pub fn create() -> (Screen, Screen) {
         let (tx, rx) = std::sync::mpsc::channel();
         let (tx2, rx2) = std::sync::mpsc::channel();
-        return (Screen{text_recv: rx, text_send: tx2, buffer: "".into()}, Screen{text_recv: rx2, text_send: tx, buffer: "".into()})
+        (Screen{text_recv: rx, text_send: tx2, buffer: "".into()}, Screen{text_recv: rx2, text_send: tx, buffer: "".into()})
     }
     pub fn send(&mut self, val: String) -> anyhow::Result<()> {
         self.text_send.send(val)?;
@@ -161,135 +186,353 @@ impl Screen {
 		match c {
 			Some(x) => {
 				self.buffer.push(x);
-				return Ok(false)
+				Ok(false)
 			},
-			None => {
-				return Ok(true)
-			}
+			None => Ok(true),
 		}
 	}
+}
 
-    /// consume all the strings in recv
-    pub fn reset(&mut self) -> anyhow::Result<()> {
-        while let Ok(x) = self.text_recv.try_recv() { }
-        Ok(())
+impl Io for Screen {
+    fn send_char(&mut self, val: char) -> anyhow::Result<()> {
+        Screen::send_char(self, val)
+    }
+    fn get_char(&mut self) -> anyhow::Result<char> {
+        Screen::get_char(self)
+    }
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        Screen::is_empty(self)
+    }
+    fn unget_char(&mut self, val: char) {
+        self.buffer.push(val);
     }
 }
 
-impl ExecutionEnv {
-    pub fn new(content: &[u8], screen: Screen, register_preset: Option<u16>) -> Self {
+impl<S: Io> ExecutionEnv<S> {
+    pub fn new(content: &[u8], screen: S, register_preset: Option<u16>) -> Self {
         let mut rv = Self {
             stack: vec![],
             memory: [0u16; 32768],
             registers: [0u16; 8],
             curr_point: 0.into(),
-            screen: screen,
+            screen,
 			register_8_preset: register_preset,
-            operation_count: 0
+            operation_count: 0,
+			hooks: Hooks::default(),
+			stopped_at: None,
+			decoded: Some(vec![None; 32768]),
+			undo: None,
+			watches: Watchpoints::default(),
+			tracer: None
         };
 
         rv.memory.copy_first(&Op::convert_bytes(content));
+        rv.add_preset_hook();
         rv
     }
 
+	/// The 8th register only gets its value once the self-test is done
+	fn add_preset_hook(&mut self) {
+		if let Some(x) = self.register_8_preset {
+			self.hooks.add(Trigger::OpCount(701400), hooks::set_register(7, x));
+		}
+	}
+
 	pub fn snapshot(&self) -> EnvSnapshot {
 		EnvSnapshot::new(self)
 	}
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
+    /// `new` with load-time patches applied, how front ends load a game image
+    pub fn with_patches(content: &[u8], screen: S, register_preset: Option<u16>, patches: &PatchSet) -> anyhow::Result<Self> {
+        let mut rv = Self::new(content, screen, register_preset);
+        rv.apply_patches(patches)?;
+        Ok(rv)
+    }
+
+    /// Apply load-time patches to memory, call before running
+    pub fn apply_patches(&mut self, patches: &PatchSet) -> anyhow::Result<()> {
+        patches.apply(&mut self.memory)?;
+        self.set_decode_cache(self.decode_cache_enabled());
+        Ok(())
+    }
+
+    pub fn decode_cache_enabled(&self) -> bool {
+        self.decoded.is_some()
+    }
+
+    /// Turn the decoded instruction cache on or off, either way it starts empty
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decoded = match enabled {
+            true => Some(vec![None; 32768]),
+            false => None,
+        };
+    }
+
+    /// Write a memory word from outside the program, e.g. a debugger. The write
+    /// gets its own undo entry rather than joining the last executed instruction
+    pub fn poke(&mut self, addr: Mem, val: MemBlock) {
+        self.begin_undo_entry();
+        self.write_mem(addr, val);
+    }
+
+    /// Set a register from outside the program, see `poke`
+    pub fn set_register(&mut self, reg: usize, val: MemBlock) {
+        self.begin_undo_entry();
+        self.registers[reg] = val;
+    }
+
+    /// Move the pc from outside the program, see `poke`
+    pub fn set_pc(&mut self, pc: Mem) {
+        self.begin_undo_entry();
+        self.curr_point = pc;
+    }
+
+    /// Start an undo entry for the state as it is now, when recording
+    fn begin_undo_entry(&mut self) {
+        if let Some(mut undo) = self.undo.take() {
+            undo.begin(self);
+            self.undo = Some(undo);
+        }
+    }
+
+    /// Write a memory word, dropping cached instructions that cover it
+    pub(crate) fn write_mem(&mut self, addr: Mem, val: MemBlock) {
+        let addr = addr.to_usize();
+        self.record(Change::Mem(addr, self.memory[addr]));
+        self.memory[addr] = val;
+        if let Some(decoded) = &mut self.decoded {
+            decoded[addr.saturating_sub(3)..=addr].fill(None);
+        }
+    }
+
+    /// Words of the instruction at the pc, zero padded past the end of memory
+    fn words_at_pc(&self) -> [u16; 4] {
+        let mut values = [0u16; 4];
+        for i in (self.curr_point.to_usize())
+            ..(self.memory.len().min(self.curr_point.to_usize() + 4))
+        {
+            values[i - self.curr_point.to_usize()] = self.memory[i];
+        }
+        values
+    }
+
+    /// Decode the instruction at the pc, or take it from the cache
+    fn fetch(&mut self) -> Result<Op, VmError> {
+        let pc = self.curr_point.to_usize();
+        if let Some(Some(op)) = self.decoded.as_ref().map(|d| d[pc]) {
+            return Ok(op);
+        }
+        let op = Op::parse(&self.words_at_pc()).map_err(|e| e.with_pc(pc))?;
+        if let Some(decoded) = &mut self.decoded {
+            decoded[pc] = Some(op);
+        }
+        Ok(op)
+    }
+
+    fn record(&mut self, change: Change) {
+        if let Some(undo) = &mut self.undo {
+            undo.record(change);
+        }
+    }
+
+    fn push_stack(&mut self, val: MemBlock) {
+        self.record(Change::Push);
+        self.stack.push(val);
+    }
+
+    fn pop_stack(&mut self) -> Option<MemBlock> {
+        let rv = self.stack.pop();
+        if let Some(v) = rv {
+            self.record(Change::Pop(v));
+        }
+        rv
+    }
+
+    /// Error for the instruction at the pc
+    pub(crate) fn fault(&self, error: impl FnOnce(usize, [u16; 4]) -> VmError) -> VmError {
+        error(self.curr_point.to_usize(), self.words_at_pc())
+    }
+
+    /// Value of operand `index` of the current instruction as a 15-bit number
+    fn resolve_num(&self, v: Val, index: usize) -> Result<Num, VmError> {
+        let value = self.resolve(v)?;
+        value.try_into().map_err(|_| self.fault(|pc, words| VmError::InvalidOperand { pc, words, index, value }))
+    }
+
+    /// A runtime address, e.g. a jump target read from a register or the stack
+    fn resolve_addr(&self, addr: MemBlock) -> Result<Mem, VmError> {
+        addr.try_into().map_err(|_| self.fault(|pc, words| VmError::AddressOutOfRange { pc, words, addr }))
+    }
+
+    /// The address of the instruction following the current one, `size` words long
+    fn next_pc(&self, size: u8) -> Result<Mem, VmError> {
+        self.resolve_addr(u16::from(self.curr_point) + size as u16)
+    }
+
+    /// Run until the program halts or needs input it doesn't have, only tests still drive the VM this way
+    #[cfg(test)]
+    pub fn run(&mut self) -> Result<(), VmError> {
         loop {
-            let mut values = [0u16; 4];
-            for i in (self.curr_point.to_usize())
-                ..(self.memory.len().min(self.curr_point.to_usize() + 4))
-            {
-                values[i - self.curr_point.to_usize()] = self.memory[i];
-            }
-            let op = Op::parse(&self.memory[self.curr_point.to_usize()..self.curr_point.to_usize()+4])?;
+            let op = self.fetch()?;
             if self.run_op(op)? {
                 break
             };
         }
         Ok(())
     }
-    fn resolve(&self, v: Val) -> anyhow::Result<MemBlock> {
-        let rv: MemBlock = match v {
+    /// Value of an operand without counting as a read for watchpoints
+    pub(crate) fn value_of(&self, v: Val) -> MemBlock {
+        match v {
             Val::Num(n) => n.into(),
             Val::Reg(r) => self.registers[r.to_usize()],
-        };
+        }
+    }
+
+    pub(crate) fn resolve(&self, v: Val) -> Result<MemBlock, VmError> {
+        let rv = self.value_of(v);
+        if let Val::Reg(r) = v {
+            if !self.watches.is_empty() {
+                self.watches.check(self.curr_point.to_usize(), Location::Reg(r.to_usize()), Access::Read, rv);
+            }
+        }
         Ok(rv)
     }
 
-    fn set_mem_from(&mut self, mem: Addr, val: Val) -> anyhow::Result<()> {
-        let value = self.resolve(val)?;
-        match mem {
-            Addr::Mem(m) => self.memory[m.to_usize()] = value,
-            Addr::Reg(r) => self.registers[r.to_usize()] = value,
+    /// Memory read by an instruction
+    fn read_mem(&self, addr: Mem) -> MemBlock {
+        let rv = self.memory[addr.to_usize()];
+        if !self.watches.is_empty() {
+            self.watches.check(self.curr_point.to_usize(), Location::Mem(addr.to_usize()), Access::Read, rv);
         }
-        Ok(())
+        rv
     }
 
-    fn set_mem(&mut self, mem: Addr, val: MemBlock) -> anyhow::Result<()> {
-        match mem {
-            Addr::Mem(m) => self.memory[m.to_usize()] = val,
-            Addr::Reg(r) => self.registers[r.to_usize()] = val,
+    fn set_mem(&mut self, mem: Addr, val: MemBlock) -> Result<(), VmError> {
+        let location = match mem {
+            Addr::Mem(m) => {
+                self.write_mem(m, val);
+                Location::Mem(m.to_usize())
+            },
+            Addr::Reg(r) => {
+                self.registers[r.to_usize()] = val;
+                Location::Reg(r.to_usize())
+            },
+        };
+        if !self.watches.is_empty() {
+            self.watches.check(self.curr_point.to_usize(), location, Access::Write, val);
         }
         Ok(())
     }
 	
-    pub fn check_teleporter(&mut self) -> anyhow::Result<bool> {
+    /// Returns true when the program ended, false when it is waiting for input
+    pub fn run_until_empty(&mut self) -> Result<bool, VmError> {
         loop {
-            let mut values = [0u16; 4];
-            for i in (self.curr_point.to_usize())
-                ..(self.memory.len().min(self.curr_point.to_usize() + 4))
-            {
-                values[i - self.curr_point.to_usize()] = self.memory[i];
+            let step = self.step()?;
+            if step.is_final() {
+                return Ok(step.halted);
             }
-            let op = Op::parse(&self.memory[self.curr_point.to_usize()..self.curr_point.to_usize()+4])?;
-			if let Op::Call(x) = &op {
-				if let Val::Num(a) = *x {
-					let b: u16 = a.into();
-					if b == 6027 {
-						return Ok(false);
-					}
-				}
-			}
-            if self.run_op(op)? {
-				break
-            };
         }
-        Ok(false)
     }
 
-    pub fn run_until_condition(&mut self, condition: fn(&ExecutionEnv) -> bool) -> anyhow::Result<()> {
-        loop {
-            let op = Op::parse(&self.memory[self.curr_point.to_usize()..self.curr_point.to_usize()+4])?;
-            if self.run_op(op)? {
-                break
-            };
-            if condition(self) {
-                break
+    /// Execute the instruction at the pc, unless it is an `in` with no input available
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        let op = self.fetch()?;
+        let pc_before = self.curr_point.to_usize();
+        let hits = self.watches.hit_count();
+        let mut rv = StepOutcome {
+            op,
+            pc_before,
+            pc_after: pc_before,
+            halted: false,
+            waiting_for_input: false,
+            stopped: false,
+        };
+        if let Op::In(_) = &op {
+            if self.screen.is_empty().map_err(|_| self.fault(|pc, words| VmError::InputClosed { pc, words }))? {
+                rv.waiting_for_input = true;
+                return Ok(rv);
             }
         }
-        Ok(())
+        if self.run_op(op)? {
+            rv.stopped = self.stopped_at.is_some() || self.watches.hit_count() > hits;
+            rv.halted = !rv.stopped;
+        }
+        rv.pc_after = self.curr_point.to_usize();
+        Ok(rv)
+    }
 
+    /// `step`, with the program ending reported as `VmError::Halt`
+    pub fn step_or_halt(&mut self) -> Result<StepOutcome, VmError> {
+        let step = self.step()?;
+        if step.halted {
+            return Err(self.fault(|pc, words| VmError::Halt { pc, words }));
+        }
+        Ok(step)
     }
 
-    pub fn run_until_empty(&mut self) -> anyhow::Result<bool> {
+    /// Step until `done` holds after a step or the outcome is final, returns the last step
+    pub fn run_until(&mut self, mut done: impl FnMut(&ExecutionEnv<S>, &StepOutcome) -> bool) -> Result<StepOutcome, VmError> {
         loop {
-            let op = Op::parse(&self.memory[self.curr_point.to_usize()..self.curr_point.to_usize()+4])?;
-			if let Op::In(_) = &op {
-				if self.screen.is_empty()? {
-					return Ok(false);
-				}
-			}
-            if self.run_op(op)? {
-                return Ok(true)
+            let step = self.step()?;
+            if done(self, &step) || step.is_final() {
+                return Ok(step);
+            }
+        }
+    }
+
+    /// Execute at most `n` instructions, calling `on_step` after each one.
+    /// Returns the last step, `None` if `n` is 0
+    pub fn run_for(&mut self, n: u64, mut on_step: impl FnMut(&ExecutionEnv<S>, &StepOutcome)) -> Result<Option<StepOutcome>, VmError> {
+        if n == 0 {
+            return Ok(None);
+        }
+        let mut count = 0;
+        self.run_until(|env, step| {
+            on_step(env, step);
+            count += 1;
+            count >= n
+        }).map(Some)
+    }
+
+    /// Run until the pc reaches `addr`, calling `on_step` after each instruction
+    pub fn run_until_pc(&mut self, addr: usize, mut on_step: impl FnMut(&ExecutionEnv<S>, &StepOutcome)) -> Result<StepOutcome, VmError> {
+        self.run_until(|env, step| {
+            on_step(env, step);
+            step.pc_after == addr
+        })
+    }
+
+    /// `exec_op`, writing a trace record when the instruction was executed
+    fn run_op(&mut self, op: Op) -> Result<bool, VmError> {
+        if self.tracer.is_none() {
+            return self.exec_op(op);
+        }
+        let pc = self.curr_point.to_usize();
+        let values = op.operands().into_iter().map(|v| self.value_of(v)).collect();
+        let registers = self.registers;
+        let count = self.operation_count;
+        let rv = self.exec_op(op);
+        if self.operation_count != count {
+            let record = TraceRecord {
+                op_count: self.operation_count,
+                pc,
+                op,
+                values,
+                reg_deltas: (0..8).filter(|r| self.registers[*r] != registers[*r]).map(|r| (r, self.registers[r])).collect(),
+                stack_depth: self.stack.len(),
             };
+            if let Some(tracer) = &mut self.tracer {
+                tracer.write(&record);
+            }
         }
+        rv
     }
 
-    fn run_op(&mut self, mut op: Op) -> anyhow::Result<bool> {
+    /// Execute one instruction after running the hooks matching it, returns true
+    /// when execution should stop (halt, empty stack return or a hook stop)
+    fn exec_op(&mut self, mut op: Op) -> Result<bool, VmError> {
         use Op::*;
+        let size = op.param_bytes();
         let mut jump_pos: Option<Mem> = None;
 
         // eprintln!("Registers: {:?}", self.registers);
@@ -297,16 +540,36 @@ impl ExecutionEnv {
         // eprintln!("Memory: {}", self.curr_point);
         // eprintln!("Count: {}", self.operation_count);
 
+        let hits = self.watches.hit_count();
+        self.begin_undo_entry();
         self.operation_count += 1;
 
-        if self.curr_point == 6027u16.try_into().unwrap() {
-            self.registers[0] = 6;
-            self.registers[1] = 4;
-            op = Op::Ret;
-        }
-        if self.operation_count == 701400 {
-            if let Some(x) = self.register_8_preset {
-                self.registers[7] = x;
+        let resuming = self.stopped_at.take() == Some(self.curr_point);
+        if !resuming && !self.hooks.is_empty() {
+            let mut hooks = std::mem::take(&mut self.hooks);
+            let action = hooks.fire(self, &op);
+            let added = std::mem::replace(&mut self.hooks, hooks);
+            self.hooks.merge(added);
+            match action {
+                HookAction::Continue => {},
+                HookAction::Skip => {
+                    // Not executed, so like `Stop` it isn't counted or kept in the undo log
+                    if let Some(undo) = &mut self.undo {
+                        undo.cancel();
+                    }
+                    self.operation_count -= 1;
+                    self.curr_point = self.next_pc(size)?;
+                    return Ok(false);
+                },
+                HookAction::Stop => {
+                    if let Some(undo) = &mut self.undo {
+                        undo.cancel();
+                    }
+                    self.operation_count -= 1;
+                    self.stopped_at = Some(self.curr_point);
+                    return Ok(true);
+                },
+                HookAction::Replace(x) => op = x,
             }
         }
         // if self.operation_count == 1168280 {
@@ -316,20 +579,23 @@ impl ExecutionEnv {
         match &op {
             Halt => {return Ok(true);},
             Set(ra, v) => {
-                self.registers[ra.to_usize()] = self.resolve(*v)?.into();
+                let v = self.resolve(*v)?;
+                self.set_mem(Addr::Reg(*ra), v)?;
             },
             Out(x) => {
                 let r  = self.resolve(*x)?;
-                self.screen.send_char(r as u8 as char)?;
+                if self.screen.send_char(r as u8 as char).is_err() {
+                    return Err(self.fault(|pc, words| VmError::OutputClosed { pc, words }));
+                }
             },
             Noop => { },
             Push(v) => {
                 let v = self.resolve(*v)?;
-                self.stack.push(v.into());
+                self.push_stack(v);
             },
             Pop(v) => {
-                let val = self.stack.pop().context("Pop from empty stack")?;
-                self.set_mem(*v, val.try_into().unwrap())?;
+                let val = self.pop_stack().ok_or_else(|| self.fault(|pc, words| VmError::EmptyStackPop { pc, words }))?;
+                self.set_mem(*v, val)?;
             },
             Eq(addr, a, b) => {
                 if self.resolve(*a)? == self.resolve(*b)? {
@@ -359,53 +625,55 @@ impl ExecutionEnv {
                 }
             },
             Add(addr, a, b) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
-                let b: u15 = self.resolve(*b)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
+                let b = self.resolve_num(*b, 3)?;
                 // self.set_mem(*addr,  a.wrapping_add(b).into())?;
                 self.set_mem(*addr,  wrapping_add(a, b).into())?;
             },
             Mult(addr, a, b) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
-                let b: u15= self.resolve(*b)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
+                let b = self.resolve_num(*b, 3)?;
                 self.set_mem(*addr,  wrapping_mul(a, b).into())?;
             },
             Mod(addr, a, b) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
-                let b: u15= self.resolve(*b)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
+                let b = self.resolve_num(*b, 3)?;
+                if b == 0.into() {
+                    return Err(self.fault(|pc, words| VmError::ModByZero { pc, words }));
+                }
                 self.set_mem(*addr,  wrapping_mod(a, b).into())?;
             },
             And(addr, a, b) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
-                let b: u15= self.resolve(*b)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
+                let b = self.resolve_num(*b, 3)?;
                 self.set_mem(*addr,  (a&b).into())?;
             },
             Or(addr, a, b) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
-                let b: u15= self.resolve(*b)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
+                let b = self.resolve_num(*b, 3)?;
                 self.set_mem(*addr,  (a|b).into())?;
             },
             Not(addr, a) => {
-                let a: u15 = self.resolve(*a)?.try_into()?;
+                let a = self.resolve_num(*a, 2)?;
                 self.set_mem(*addr,  (bit_not(a)).into())?;
             },
             Call(addr) => {
                 // let next_execution = 
-                let bts: u15 = op.param_bytes().into();
-                let next_execution = self.curr_point + bts;
-                self.stack.push(next_execution.into());
-
                 let loc = self.resolve(*addr)?;
-                jump_pos = Some(loc.try_into()?);
+                jump_pos = Some(self.resolve_addr(loc)?);
+
+                let next_execution = self.next_pc(size)?;
+                self.push_stack(next_execution.into());
             },
             Rmem(addr, a) => {
                 let m = match a {
                     Addr::Mem(x) => {
                         let x: u15 = *x;
-                        self.memory[x.to_usize()]
+                        self.read_mem(x)
                     }, 
                     Addr::Reg(r) => {
-                        let x: u15 = self.resolve(Val::Reg(*r))?.try_into()?;
-                        self.memory[x.to_usize()]
+                        let x: u15 = self.resolve_addr(self.resolve(Val::Reg(*r))?)?;
+                        self.read_mem(x)
                     }
                 };
                 self.set_mem(*addr,  m)?;
@@ -415,16 +683,16 @@ impl ExecutionEnv {
                 
                 let b: Val = (*addr).into();
                 let x6 = self.resolve(b)?;
-                let x: u15 = x6.try_into()?;
+                let x: u15 = self.resolve_addr(x6)?;
                 
                 // assert!((x6 as usize) < include_bytes!("../challenge.bin").len()/2);
 
                 self.set_mem(Addr::Mem(x),  self.resolve(*a)?)?;
             },
             Ret => {
-                match self.stack.pop() {
+                match self.pop_stack() {
                     Some(x) => {
-                        jump_pos = Some(x.try_into()?)
+                        jump_pos = Some(self.resolve_addr(x)?)
                     },
                     None => return Ok(true)
                 }
@@ -433,22 +701,23 @@ impl ExecutionEnv {
 				// if let Some(x) = self.register_8_preset {
 				// 	self.registers[7] = x;
 				// }
-                let v = self.screen.get_char()?;
+                let v = self.screen.get_char().map_err(|_| self.fault(|pc, words| VmError::InputClosed { pc, words }))?;
+                self.record(Change::Input(v));
                 self.set_mem(*x, v as u16)?;
             }
 
         };
         match jump_pos {
             None => {
-                let bts: u15 = op.param_bytes().into();
-                self.curr_point = self.curr_point + bts;
+                self.curr_point = self.next_pc(size)?;
             },
             Some(x) => {
                 self.curr_point = x;
             }
         }
 
-        return Ok(false);
+        // Stop after instructions that hit a watchpoint
+        Ok(self.watches.hit_count() > hits)
     }
 }
 
@@ -461,15 +730,16 @@ pub struct StaticExecuter {
 }
 
 impl StaticExecuter {
-    pub fn new() -> Self {
+    pub fn new(patches: &PatchSet) -> anyhow::Result<Self> {
         let bytes = include_bytes!("../challenge.bin");
         let (s1, s2) = Screen::create();
-        Self {
-            env: ExecutionEnv::new(bytes, s1, Some(25734)),
+        let env = ExecutionEnv::with_patches(bytes, s1, Some(25734), patches)?;
+        Ok(Self {
+            env,
             env_screen: s2,
             ended: false,
             history: Vec::new()
-        }
+        })
     }
     pub fn get_history(&self) -> Vec<String> {
         self.history.clone()
@@ -477,17 +747,19 @@ impl StaticExecuter {
     pub fn is_finished(&self) -> bool {
         self.ended
     }
+    pub fn snapshot(&self) -> EnvSnapshot {
+        self.env.snapshot()
+    }
 
-    pub fn new_from_checkpoint(history: Vec<String>) -> anyhow::Result<Self> {
-        let mut rv = Self::new();
-        for s in history.iter() {
-            rv.env_screen.send(s.to_string())?;
-        }
-        rv.history = history;
-        Ok(rv)
+    /// Trace the instructions executed from now on to `path`, see `trace::TraceWriter`
+    pub fn start_trace(&mut self, path: &std::path::Path) -> anyhow::Result<()> {
+        let file = File::create(path).with_context(|| format!("Creating trace {:?}", path))?;
+        self.env.start_trace(Box::new(std::io::BufWriter::new(file)))?;
+        Ok(())
     }
     pub fn bootstrap(&mut self) -> anyhow::Result<String> {
         self.env.run_until_empty()?;
+        self.env.flush_trace()?;
         let rv = self.env_screen.get_all()?;
         Ok(rv)
     }
@@ -500,9 +772,21 @@ impl StaticExecuter {
         if self.env.run_until_empty()? {
             self.ended = true;
         };
+        self.env.flush_trace()?;
         let rv = self.env_screen.get_all()?;
         Ok(Some(rv))
     }
+    /// Set a register or memory word of the live env. Kept in the history so replays
+    /// make the write at the same point, see `checkpoint::replay`
+    pub fn write(&mut self, w: RuntimeWrite) -> anyhow::Result<()> {
+        w.apply(&mut self.env)?;
+        self.history.push(format!("{}\n", w));
+        Ok(())
+    }
+    pub fn peek(&self, addr: usize, count: usize) -> &[MemBlock] {
+        let memory = &self.env.memory[..];
+        &memory[addr.min(memory.len())..addr.saturating_add(count).min(memory.len())]
+    }
 
 }
 
@@ -529,4 +813,114 @@ mod tests {
         assert_eq!(wrapping_mul(a, b), c);
     }
 
+    #[test]
+    fn test_step() {
+        use crate::assembler::{assemble, to_bytes};
+        let words = assemble("set r0 3\nloop: add r0 r0 32767\njt r0 loop\nin r1\nout r1\nhalt").unwrap();
+        let (screen, mut user) = Screen::create();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), screen, None);
+
+        let step = env.step().unwrap();
+        assert_eq!((step.pc_before, step.pc_after, step.halted), (0, 3, false));
+        assert_eq!(step.op.name(), "set");
+
+        let mut pcs = vec![];
+        let last = env.run_for(2, |_, s| pcs.push(s.pc_after)).unwrap().unwrap();
+        assert_eq!(pcs, vec![7, 3]);
+        assert_eq!(last.op.name(), "jt");
+
+        let step = env.run_until_pc(10, |_, _| {}).unwrap();
+        assert_eq!(step.pc_after, 10);
+        assert_eq!(env.registers[0], 0);
+
+        let step = env.run_until(|_, _| false).unwrap();
+        assert!(step.waiting_for_input && step.pc_after == 10);
+        user.send("x".to_string()).unwrap();
+        let step = env.run_until(|_, _| false).unwrap();
+        assert!(step.halted);
+        assert_eq!(user.get_all().unwrap(), "x");
+        assert_eq!(env.operation_count, 10);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        use crate::assembler::{assemble, to_bytes};
+        // The second pass through the loop must see the rewritten `out` operand
+        let words = assemble("set r0 0\nloop: out 'a'\njt r0 end\nset r0 1\nwmem 4 'b'\njmp loop\nend: halt").unwrap();
+        for cached in [true, false] {
+            let (screen, mut user) = Screen::create();
+            let mut env = ExecutionEnv::new(&to_bytes(&words), screen, None);
+            env.set_decode_cache(cached);
+            env.run().unwrap();
+            assert_eq!(user.get_all().unwrap(), "ab");
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        use crate::assembler::{assemble, to_bytes};
+        let run = |source: &str| {
+            let (screen, _user) = Screen::create();
+            let mut env = ExecutionEnv::new(&to_bytes(&assemble(source).unwrap()), screen, None);
+            env.run().unwrap_err()
+        };
+        assert_eq!(run("noop\npop r0"), VmError::EmptyStackPop { pc: 1, words: [3, 32768, 0, 0] });
+        assert_eq!(run("mod r0 5 r1"), VmError::ModByZero { pc: 0, words: [11, 32768, 5, 32769] });
+        assert_eq!(run("rmem r0 data\nadd r1 r0 1\ndata: .word r7"),
+            VmError::InvalidOperand { pc: 3, words: [9, 32769, 32768, 1], index: 2, value: 32775 });
+        assert_eq!(run("rmem r0 data\npush r0\nret\ndata: .word r7"),
+            VmError::AddressOutOfRange { pc: 5, words: [18, 32775, 0, 0], addr: 32775 });
+        assert_eq!(run(".word 25"), VmError::InvalidOpcode { pc: 0, words: [25, 0, 0, 0] });
+        let (screen, user) = Screen::create();
+        drop(user);
+        let mut env = ExecutionEnv::new(&to_bytes(&assemble("out 'a'").unwrap()), screen, None);
+        assert_eq!(env.run().unwrap_err(), VmError::OutputClosed { pc: 0, words: [19, 97, 0, 0] });
+
+        // Running off the end of memory
+        let mut words = vec![0u16; 32768];
+        words[..2].copy_from_slice(&[6, 32767]);
+        words[32767] = 21;
+        let (screen, _user) = Screen::create();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), screen, None);
+        assert_eq!(env.run().unwrap_err(), VmError::AddressOutOfRange { pc: 32767, words: [21, 0, 0, 0], addr: 32768 });
+    }
+
+    #[test]
+    fn test_runtime_writes() {
+        let mut executer = StaticExecuter::new(&PatchSet::default()).unwrap();
+        executer.bootstrap().unwrap();
+        executer.write("!reg r7 5".parse().unwrap()).unwrap();
+        executer.write("!poke 2732 0".parse().unwrap()).unwrap();
+        assert!(executer.write("!poke 32768 0".parse().unwrap()).is_err());
+        assert!(executer.write("!reg r0 32768".parse().unwrap()).is_err());
+        // Memory also takes register references
+        executer.write("!poke 2733 32775".parse().unwrap()).unwrap();
+        assert!(executer.write("!poke 2733 32776".parse().unwrap()).is_err());
+        assert_eq!(executer.peek(2732, 2), &[0, 32775]);
+        assert_eq!(executer.peek(32767, 2).len(), 1);
+        assert_eq!(executer.env.registers[7], 5);
+        assert_eq!(executer.get_history(), vec!["!reg r7 5\n", "!poke 2732 0\n", "!poke 2733 32775\n"]);
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let mut executer = StaticExecuter::new(&PatchSet::default()).unwrap();
+        executer.bootstrap().unwrap();
+        executer.execute("take tablet\n".to_string()).unwrap();
+        executer.write("!reg r7 5".parse().unwrap()).unwrap();
+        executer.write("!poke 32000 7".parse().unwrap()).unwrap();
+        executer.execute("use tablet\n".to_string()).unwrap();
+
+        // Saved as the `save` command does, then replayed on a fresh env
+        let saved = serde_json::to_string_pretty(&executer.get_history()).unwrap();
+        let lines = crate::checkpoint::parse(&serde_json::from_str::<Vec<String>>(&saved).unwrap()).unwrap();
+        let (screen, mut user) = Screen::create();
+        let mut env = ExecutionEnv::new(include_bytes!("../challenge.bin"), screen, Some(25734));
+        crate::checkpoint::replay(&mut env, &lines, |_, s| user.send(s.to_string())).unwrap();
+        env.run_until_empty().unwrap();
+        assert_eq!((env.registers, env.operation_count), (executer.env.registers, executer.env.operation_count));
+        assert_eq!((env.registers[7], env.memory[32000]), (5, 7));
+        assert!(env.memory == executer.env.memory);
+    }
+
 }
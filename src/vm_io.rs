@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Context};
+use crate::error::VmError;
+use crate::vm::ExecutionEnv;
+
+/// The interpreter's side of the screen: `out` writes chars, `in` reads them.
+/// `ExecutionEnv` is generic over it so every front end shares one `run_op`.
+pub trait Io {
+    fn send_char(&mut self, val: char) -> anyhow::Result<()>;
+    /// Next input char, blocking until there is one
+    fn get_char(&mut self) -> anyhow::Result<char>;
+    /// Whether `get_char` would block
+    fn is_empty(&mut self) -> anyhow::Result<bool>;
+    /// Put a char back so it is the next one read
+    fn unget_char(&mut self, val: char);
+}
+
+/// Screen over tokio channels, the async counterpart of `vm::Screen`
+pub struct AsyncScreen {
+    pub(crate) text_recv: tokio::sync::mpsc::UnboundedReceiver<String>,
+    pub(crate) text_send: tokio::sync::mpsc::UnboundedSender<String>,
+    pub(crate) buffer: String
+}
+
+impl AsyncScreen {
+    pub fn create() -> (AsyncScreen, AsyncScreen) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx2, rx2) = tokio::sync::mpsc::unbounded_channel();
+        (AsyncScreen{text_recv: rx, text_send: tx2, buffer: "".into()}, AsyncScreen{text_recv: rx2, text_send: tx, buffer: "".into()})
+    }
+    pub fn send(&mut self, val: String) -> anyhow::Result<()> {
+        self.text_send.send(val)?;
+        Ok(())
+    }
+    /// Stop sending, the other end sees the channel closed once it read what was sent
+    pub fn close(&mut self) {
+        let (closed, _) = tokio::sync::mpsc::unbounded_channel();
+        self.text_send = closed;
+    }
+    /// Wait until there is input to read
+    pub async fn wait_input(&mut self) -> anyhow::Result<()> {
+        while self.buffer.is_empty() {
+            let data = self.text_recv.recv().await.context("MPSC died")?;
+            self.buffer = data.chars().rev().collect();
+        }
+        Ok(())
+    }
+    pub async fn get_char(&mut self) -> anyhow::Result<char> {
+        self.wait_input().await?;
+        Ok(self.buffer.pop().unwrap())
+    }
+    /// Everything received so far without waiting
+    pub fn get_all(&mut self) -> anyhow::Result<String> {
+        let mut rv: String = self.buffer.chars().rev().collect();
+        self.buffer.clear();
+        loop {
+            match self.text_recv.try_recv() {
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                    bail!("Disconnected screen get");
+                },
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                    return Ok(rv)
+                }
+                Ok(x) => {
+                    rv += &x;
+                }
+            };
+        }
+    }
+}
+
+impl Io for AsyncScreen {
+    fn send_char(&mut self, val: char) -> anyhow::Result<()> {
+        self.send(val.to_string())
+    }
+    /// Never blocks, as it runs inside the runtime: fails when nothing is queued.
+    /// `ExecutionEnv::run_async` waits with `wait_input` before an `in` instead
+    fn get_char(&mut self) -> anyhow::Result<char> {
+        if self.is_empty()? {
+            bail!("No input queued");
+        }
+        Ok(self.buffer.pop().unwrap())
+    }
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        while self.buffer.is_empty() {
+            match self.text_recv.try_recv() {
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                    bail!("Disconnected screen get");
+                },
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                    return Ok(true)
+                }
+                Ok(x) => {
+                    self.buffer = x.chars().rev().collect();
+                }
+            };
+        }
+        Ok(false)
+    }
+    fn unget_char(&mut self, val: char) {
+        self.buffer.push(val);
+    }
+}
+
+impl ExecutionEnv<AsyncScreen> {
+    /// Like `run`, but waits for input without blocking the runtime
+    pub async fn run_async(&mut self) -> Result<(), VmError> {
+        loop {
+            let step = self.step()?;
+            if step.waiting_for_input {
+                if self.screen.wait_input().await.is_err() {
+                    return Err(self.fault(|pc, words| VmError::InputClosed { pc, words }));
+                }
+            } else if step.is_final() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Fixed input and collected output, for tests and batch runs
+#[derive(Debug, Clone, Default)]
+pub struct BufferIo {
+    pub input: VecDeque<char>,
+    pub output: String,
+}
+
+impl BufferIo {
+    pub fn new(input: &str) -> Self {
+        Self { input: input.chars().collect(), output: String::new() }
+    }
+}
+
+impl Io for BufferIo {
+    fn send_char(&mut self, val: char) -> anyhow::Result<()> {
+        self.output.push(val);
+        Ok(())
+    }
+    /// Fails once the input is used up, there is nobody to wait for
+    fn get_char(&mut self) -> anyhow::Result<char> {
+        self.input.pop_front().context("Input exhausted")
+    }
+    fn is_empty(&mut self) -> anyhow::Result<bool> {
+        Ok(self.input.is_empty())
+    }
+    fn unget_char(&mut self, val: char) {
+        self.input.push_front(val);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+
+    const ECHO: &str = "
+        loop: in r0
+        out r0
+        eq r1 r0 10
+        jf r1 loop
+        halt
+    ";
+
+    #[test]
+    fn test_buffer_io() {
+        let image = to_bytes(&assemble(ECHO).unwrap());
+        let mut env = ExecutionEnv::new(&image, BufferIo::new("hi\n"), None);
+        env.run().unwrap();
+        assert_eq!(env.screen.output, "hi\n");
+
+        let mut env = ExecutionEnv::new(&image, BufferIo::new("hi"), None);
+        assert!(matches!(env.run(), Err(VmError::InputClosed { pc: 0, .. })));
+        assert_eq!(env.screen.output, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_async_screen() {
+        let image = to_bytes(&assemble(ECHO).unwrap());
+        let (screen, mut user) = AsyncScreen::create();
+        let mut env = ExecutionEnv::new(&image, screen, None);
+        // Hand the env back so the screen isn't disconnected before reading it
+        let task = tokio::spawn(async move { env.run_async().await.map(|_| env) });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        user.send("hi\n".to_string()).unwrap();
+        let env = task.await.unwrap().unwrap();
+        assert_eq!(user.get_all().unwrap(), "hi\n");
+        assert_eq!(env.operation_count, 13);
+
+        // Reading without input fails rather than blocking the runtime
+        let (mut screen, _user) = AsyncScreen::create();
+        assert!(Io::get_char(&mut screen).is_err());
+    }
+}
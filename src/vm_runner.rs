@@ -1,63 +1,78 @@
-use std::collections::VecDeque;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
 
-use crate::{async_vm::Screen};
-use anyhow::Context;
-use tokio::{io::{self, AsyncBufReadExt}, select};
-use either::*;
+use crate::error::VmError;
+use crate::vm::ExecutionEnv;
+use crate::vm_io::AsyncScreen as Screen;
 
-
-struct Runner {
+/// Plays an `ExecutionEnv<AsyncScreen>` on the tokio runtime: the VM runs in its own task,
+/// input lines are sent to it and its output is written as it comes
+pub struct Runner {
 	screen: Screen,
-	buffer_history: String,
-	stats: Stats
 }
 
 impl Runner {
 	pub fn new(screen: Screen) -> Self {
-		Self {screen, buffer_history: "".into()}
+		Self { screen }
 	}
-	pub fn new_with_preset(mut screen: Screen, preset: Vec<String>) -> anyhow::Result<Self> {
-		for p in preset {
-			screen.send(p)?;
-		}
 
-		Ok(Self::new(screen))
-	}
-
-	pub fn push_char_history(&mut self, c: char) {
-		self.buffer_history.push(c);
-		if self.buffer_history.len() > 40 {
-			self.buffer_history.remove(0);
-		}
-	}
-
-	pub async fn run(&mut self) -> anyhow::Result<()> {
+	/// Run `env` until it halts, or needs input once `input` is exhausted.
+	/// The env is boxed as it would be copied around on the stack with the futures
+	pub async fn run(&mut self, mut env: Box<ExecutionEnv<Screen>>, input: impl AsyncBufRead + Unpin, out: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+		let mut vm = tokio::spawn(async move {
+			let rv = env.run_async().await;
+			// Handed back so the screen stays connected until the output is read
+			(rv, env)
+		});
+		let mut lines = input.lines();
+		let mut input_open = true;
 		loop {
-			let stdin = io::stdin();
-			let reader = tokio::io::BufReader::new(stdin);
-			let mut lines = reader.lines();
-
-			// Moving out from select!() for IDE support
-			let mut act = None;
 			select! {
-				line = lines.next_line() => {
-					act = Some(Left(line?.context("Stdin closed")?));
+				line = lines.next_line(), if input_open => match line? {
+					Some(line) => self.screen.send(format!("{}\n", line))?,
+					None => {
+						// The VM stops with InputClosed once it wants more
+						self.screen.close();
+						input_open = false;
+					}
 				},
-				out = self.screen.get_char() => {
-					act = Some(Right(out?));
-				}
-			}
-
-			match act.unwrap() {
-				Left(x) => {
-					// Check if custom command
+				c = self.screen.get_char() => {
+					let mut buf = [0u8; 4];
+					out.write_all(c?.encode_utf8(&mut buf).as_bytes()).await?;
+					out.flush().await?;
 				},
-				Right(x) => {
-					self.push_char_history(x);
-					
+				done = &mut vm => {
+					let (rv, _env) = done?;
+					out.write_all(self.screen.get_all()?.as_bytes()).await?;
+					out.flush().await?;
+					return match rv {
+						Ok(()) | Err(VmError::InputClosed { .. }) => Ok(()),
+						Err(e) => Err(e.into()),
+					};
 				}
 			}
-
 		}
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::assembler::{assemble, to_bytes};
+
+	#[tokio::test]
+	async fn test_runner() {
+		let image = to_bytes(&assemble("
+			loop: in r0
+			out r0
+			jmp loop
+		").unwrap());
+		let (screen, mut user) = Screen::create();
+		let env = Box::new(ExecutionEnv::new(&image, screen, None));
+		let mut out = vec![];
+		user.send("a\n".to_string()).unwrap();
+		let mut runner = Runner::new(user);
+		runner.run(env, &b"bc\nd"[..], &mut out).await.unwrap();
+		assert_eq!(String::from_utf8(out).unwrap(), "a\nbc\nd\n");
+	}
+}
@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+
+use crate::op_parser::*;
+
+/// A memory word or register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Mem(usize),
+    Reg(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    /// Memory addresses `start..=end`
+    Mem(usize, usize),
+    Reg(usize),
+}
+
+impl WatchTarget {
+    fn contains(&self, location: Location) -> bool {
+        match (*self, location) {
+            (Self::Mem(start, end), Location::Mem(a)) => start <= a && a <= end,
+            (Self::Reg(r), Location::Reg(x)) => r == x,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// A write storing this value
+    Equals(MemBlock),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchId(usize);
+
+/// One access that matched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: WatchId,
+    /// Address of the instruction doing the access
+    pub pc: usize,
+    pub location: Location,
+    pub access: Access,
+    /// Value read or written
+    pub value: MemBlock,
+}
+
+/// Watchpoints checked on register and memory accesses done by instructions.
+/// `ExecutionEnv` stops after an instruction that hit one, the hits are kept until taken.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watches: Vec<(WatchId, WatchTarget, WatchKind)>,
+    next_id: usize,
+    /// Interior mutability as reads happen in `ExecutionEnv::resolve(&self)`
+    hits: RefCell<Vec<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, target: WatchTarget, kind: WatchKind) -> WatchId {
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        self.watches.push((id, target, kind));
+        id
+    }
+
+    pub fn remove(&mut self, id: WatchId) {
+        self.watches.retain(|(i, _, _)| *i != id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub(crate) fn hit_count(&self) -> usize {
+        self.hits.borrow().len()
+    }
+
+    /// Hits since the last call
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(self.hits.get_mut())
+    }
+
+    pub(crate) fn check(&self, pc: usize, location: Location, access: Access, value: MemBlock) {
+        for (id, target, kind) in self.watches.iter() {
+            let matches = match kind {
+                WatchKind::Read => access == Access::Read,
+                WatchKind::Write => access == Access::Write,
+                WatchKind::Equals(v) => access == Access::Write && value == *v,
+            };
+            if matches && target.contains(location) {
+                self.hits.borrow_mut().push(WatchHit { id: *id, pc, location, access, value });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, to_bytes};
+    use crate::vm::ExecutionEnv;
+    use crate::vm_io::BufferIo;
+
+    #[test]
+    fn test_watchpoints() {
+        let words = assemble("
+            set r0 1
+            add r1 r0 r0
+            jf r7 skip
+            noop
+            skip: wmem 100 r1
+            rmem r2 101
+            wmem 101 5
+            halt
+        ").unwrap();
+        let mut env = ExecutionEnv::new(&to_bytes(&words), BufferIo::new(""), None);
+        let r7 = env.watches.add(WatchTarget::Reg(7), WatchKind::Read);
+        let mem = env.watches.add(WatchTarget::Mem(100, 101), WatchKind::Write);
+        let five = env.watches.add(WatchTarget::Mem(0, 32767), WatchKind::Equals(5));
+        env.watches.add(WatchTarget::Mem(101, 101), WatchKind::Read);
+
+        let step = env.run_until(|_, _| false).unwrap();
+        assert!(step.stopped && !step.halted);
+        assert_eq!(step.pc_after, 11);
+        assert_eq!(env.watches.take_hits(), vec![
+            WatchHit { id: r7, pc: 7, location: Location::Reg(7), access: Access::Read, value: 0 },
+        ]);
+
+        env.run().unwrap();
+        assert_eq!(env.watches.take_hits()[0].location, Location::Mem(100));
+        env.run().unwrap();
+        assert_eq!(env.watches.take_hits()[0].access, Access::Read);
+        env.watches.remove(mem);
+        env.run().unwrap();
+        assert_eq!(env.watches.take_hits(), vec![
+            WatchHit { id: five, pc: 17, location: Location::Mem(101), access: Access::Write, value: 5 },
+        ]);
+        assert!(env.run_until(|_, _| false).unwrap().halted);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::annotations::Annotations;
+use crate::op_parser::*;
+use crate::reverse_engineer::{fmt_op, trace_code};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XrefKind {
+	/// `jmp`, `jt` or `jf` to the address
+	Jump,
+	Call,
+	/// `rmem` from a constant address
+	Read,
+	/// `wmem` to a constant address, or an arithmetic op storing straight into memory
+	Write,
+}
+
+impl XrefKind {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Jump => "jump",
+			Self::Call => "call",
+			Self::Read => "read",
+			Self::Write => "write",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Xref {
+	/// Address of the referencing instruction
+	pub from: usize,
+	pub kind: XrefKind,
+	pub op: Op,
+}
+
+/// Every statically known reference to every address, collected from the code
+/// reachable from the given roots
+#[derive(Debug, Default)]
+pub struct XrefIndex {
+	refs: BTreeMap<usize, Vec<Xref>>,
+}
+
+fn references(op: &Op) -> Vec<(usize, XrefKind)> {
+	match *op {
+		Op::Jmp(m) | Op::Jt(_, m) | Op::Jf(_, m) => vec![(m.to_usize(), XrefKind::Jump)],
+		Op::Call(Val::Num(m)) => vec![(m.to_usize(), XrefKind::Call)],
+		Op::Rmem(dest, Addr::Mem(m)) => {
+			let mut rv = vec![(m.to_usize(), XrefKind::Read)];
+			if let Addr::Mem(d) = dest {
+				rv.push((d.to_usize(), XrefKind::Write));
+			}
+			rv
+		},
+		Op::Wmem(Addr::Mem(m), _) => vec![(m.to_usize(), XrefKind::Write)],
+		Op::Pop(Addr::Mem(m))
+		| Op::In(Addr::Mem(m))
+		| Op::Not(Addr::Mem(m), _)
+		| Op::Eq(Addr::Mem(m), _, _)
+		| Op::Gt(Addr::Mem(m), _, _)
+		| Op::Add(Addr::Mem(m), _, _)
+		| Op::Mult(Addr::Mem(m), _, _)
+		| Op::Mod(Addr::Mem(m), _, _)
+		| Op::And(Addr::Mem(m), _, _)
+		| Op::Or(Addr::Mem(m), _, _) => vec![(m.to_usize(), XrefKind::Write)],
+		_ => vec![],
+	}
+}
+
+impl XrefIndex {
+	pub fn build(memory: &[u16], roots: &[usize]) -> Self {
+		let mut rv = Self::default();
+		for (addr, op) in trace_code(memory, roots) {
+			for (target, kind) in references(&op) {
+				rv.refs.entry(target).or_default().push(Xref { from: addr, kind, op });
+			}
+		}
+		rv
+	}
+
+	pub fn get(&self, addr: usize) -> &[Xref] {
+		self.refs.get(&addr).map(|v| v.as_slice()).unwrap_or(&[])
+	}
+
+	/// Human readable listing of the references to `addr`
+	pub fn format(&self, addr: usize, ann: &Annotations) -> String {
+		let refs = self.get(addr);
+		let mut rv = format!("{} references to {}\n", refs.len(), ann.symbol(addr));
+		for x in refs {
+			writeln!(rv, "{:>5}  {:<6} {}", x.from, x.kind.name(), fmt_op(&x.op, ann)).unwrap();
+		}
+		rv
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn test_index() {
+		// 0: call 9; 2: rmem r0 20; 5: wmem 20 r0; 8: halt; 9: jt r0 0; 12: ret
+		let mem = [17, 9, 15, 32768, 20, 16, 20, 32768, 0, 7, 32768, 0, 18];
+		let index = XrefIndex::build(&mem, &[0]);
+		let kinds = |a| index.get(a).iter().map(|x| (x.from, x.kind)).collect::<Vec<_>>();
+		assert_eq!(kinds(9), vec![(0, XrefKind::Call)]);
+		assert_eq!(kinds(20), vec![(2, XrefKind::Read), (5, XrefKind::Write)]);
+		assert_eq!(kinds(0), vec![(9, XrefKind::Jump)]);
+		assert!(index.get(12).is_empty());
+	}
+}
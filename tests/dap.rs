@@ -0,0 +1,169 @@
+//! Drives the `dap` subcommand over stdio like an editor would
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use serde_json::{json, Value};
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: u64,
+    /// Events read while waiting for something else
+    events: Vec<Value>,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_synacor_challenge"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(["-a", "annotations.json", "dap"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Self { child, stdin, stdout, seq: 0, events: vec![] }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            assert!(self.stdout.read_line(&mut line).unwrap() > 0, "adapter closed stdout");
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(v) = line.strip_prefix("Content-Length:") {
+                length = v.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0u8; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({"seq": self.seq, "type": "request", "command": command, "arguments": arguments}).to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let msg = self.read();
+            if msg["type"] == "response" && msg["request_seq"] == self.seq {
+                assert_eq!(msg["command"], command);
+                return msg;
+            }
+            self.events.push(msg);
+        }
+    }
+
+    /// The first event named `event` matching `pred`, earlier ones are dropped
+    fn wait_event(&mut self, event: &str, pred: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let msg = match self.events.is_empty() {
+                true => self.read(),
+                false => self.events.remove(0),
+            };
+            if msg["event"] == event && pred(&msg["body"]) {
+                return msg["body"].clone();
+            }
+        }
+    }
+
+    fn top_frame(&mut self) -> Value {
+        let trace = self.request("stackTrace", json!({"threadId": 1}));
+        trace["body"]["stackFrames"][0].clone()
+    }
+}
+
+#[test]
+fn test_debug_session() {
+    let mut dap = Client::start();
+    let init = dap.request("initialize", json!({"adapterID": "synacor"}));
+    assert_eq!(init["body"]["supportsDisassembleRequest"], true);
+    dap.wait_event("initialized", |_| true);
+
+    let bad = dap.request("launch", json!({"program": "challenge.bin", "register8": 65536 + 25734}));
+    assert_eq!(bad["success"], false);
+    assert_eq!(bad["message"], "register8 out of range: 91270");
+    assert_eq!(dap.request("launch", json!({"program": "challenge.bin", "stopOnEntry": true}))["success"], true);
+    let bps = dap.request("setFunctionBreakpoints", json!({"breakpoints": [
+        {"name": "for_each", "condition": "r0 != 0"},
+        {"name": "nowhere"},
+        {"name": "print_number", "condition": "r9 == 1"},
+    ]}));
+    assert_eq!(bps["body"]["breakpoints"][0]["verified"], true);
+    assert_eq!(bps["body"]["breakpoints"][1]["verified"], false);
+    assert_eq!(bps["body"]["breakpoints"][2]["verified"], false);
+    dap.request("configurationDone", json!({}));
+    dap.wait_event("stopped", |b| b["reason"] == "entry");
+    assert_eq!(dap.top_frame()["instructionPointerReference"], "0");
+
+    dap.request("continue", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "function breakpoint");
+    let frame = dap.top_frame();
+    assert_eq!(frame["instructionPointerReference"], "1458");
+    assert!(frame["name"].as_str().unwrap().starts_with("for_each: "));
+
+    let regs = dap.request("variables", json!({"variablesReference": 1}));
+    let regs = regs["body"]["variables"].as_array().unwrap().clone();
+    assert_eq!(regs.len(), 9);
+    assert_eq!(regs[8], json!({"name": "pc", "value": "1458", "variablesReference": 0}));
+    let set = dap.request("setVariable", json!({"variablesReference": 1, "name": "r6", "value": "5"}));
+    assert_eq!(set["body"]["value"], "5");
+
+    let disas = dap.request("disassemble", json!({"memoryReference": "1458", "instructionOffset": -2, "instructionCount": 5}));
+    let instructions = disas["body"]["instructions"].as_array().unwrap();
+    assert_eq!(instructions.len(), 5);
+    assert_eq!(instructions[2]["address"], "1458");
+    assert_eq!(instructions[2]["symbol"], "for_each");
+    let memory = dap.request("readMemory", json!({"memoryReference": "for_each", "count": 4}));
+    assert_eq!(memory["body"]["address"], "1458");
+    assert_eq!(memory["body"]["data"].as_str().unwrap().len(), 8);
+
+    dap.request("stepIn", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "step");
+    assert_ne!(dap.top_frame()["instructionPointerReference"], "1458");
+
+    // Back over the step, then over setting r6
+    assert_eq!(init["body"]["supportsStepBack"], true);
+    dap.request("stepBack", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "step");
+    assert_eq!(dap.top_frame()["instructionPointerReference"], "1458");
+    let r6 = |dap: &mut Client| dap.request("variables", json!({"variablesReference": 1}))["body"]["variables"][6]["value"].clone();
+    assert_eq!(r6(&mut dap), "5");
+    dap.request("stepBack", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "step");
+    assert_ne!(r6(&mut dap), "5");
+    // No earlier hit of the breakpoint, so back to the oldest recorded step
+    dap.request("reverseContinue", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "step");
+    assert_ne!(dap.top_frame()["instructionPointerReference"], "1458");
+
+    // Out of `for_each` to the caller, past the calls it makes
+    dap.request("continue", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "function breakpoint");
+    let trace = dap.request("stackTrace", json!({"threadId": 1}));
+    let caller = trace["body"]["stackFrames"][1]["instructionPointerReference"].clone();
+    dap.request("stepOut", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "step");
+    assert_eq!(dap.top_frame()["instructionPointerReference"], caller);
+
+    // Without breakpoints the game runs until it wants input, then plays along with the console
+    dap.request("setFunctionBreakpoints", json!({"breakpoints": []}));
+    dap.request("continue", json!({"threadId": 1}));
+    dap.wait_event("output", |b| b["output"].as_str().unwrap().contains("What do you do?"));
+    let eval = dap.request("evaluate", json!({"expression": "look", "context": "repl"}));
+    assert_eq!(eval["success"], true);
+    dap.wait_event("output", |b| b["output"].as_str().unwrap().contains("== Foothills =="));
+    dap.request("pause", json!({"threadId": 1}));
+    dap.wait_event("stopped", |b| b["reason"] == "pause");
+
+    assert_eq!(dap.request("bogus", json!({}))["success"], false);
+    assert_eq!(dap.request("disconnect", json!({}))["success"], true);
+    assert!(dap.child.wait().unwrap().success());
+}